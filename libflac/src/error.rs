@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::{io, path::PathBuf};
 
 use thiserror::Error;
 
@@ -10,6 +10,8 @@ pub enum Error {
   EncoderInitFailure(u32),
   #[error("Error occurred while encoding")]
   EncodeFailure,
+  #[error("Failed to write the encoded data: {0}")]
+  Io(#[from] io::Error),
 }
 
 pub type Result<T> = ::std::result::Result<T, Error>;
@@ -1,6 +1,13 @@
 mod error;
 
-use std::{ffi::CString, mem::ManuallyDrop, path::Path, ptr::NonNull};
+use std::{
+  ffi::{c_void, CString},
+  io::{self, Write},
+  mem::ManuallyDrop,
+  path::Path,
+  ptr::NonNull,
+  slice,
+};
 
 use libflac_sys::*;
 use paste::paste;
@@ -38,7 +45,18 @@ impl Encoder {
 
   set_settings!(channels, bits_per_sample, sample_rate, compression_level);
 
-  pub fn init_file(self, path: impl AsRef<Path>) -> Result<InitializedEncoder> {
+  // Written into STREAMINFO, a stream without seeking can not fix it afterwards
+  pub fn set_total_samples_estimate(self, total_samples_estimate: u64) -> Self {
+    let res = unsafe {
+      FLAC__stream_encoder_set_total_samples_estimate(self.0.as_ptr(), total_samples_estimate)
+    };
+    if res == 0 {
+      panic!("the encoder is already initialized");
+    }
+    self
+  }
+
+  pub fn init_file(self, path: impl AsRef<Path>) -> Result<InitializedEncoder<'static>> {
     let path = path.as_ref();
     let raw_path = CString::new(path.as_os_str().as_encoded_bytes())
       .map_err(|_| Error::PathEncoding(path.to_owned()))?;
@@ -54,7 +72,61 @@ impl Encoder {
       return Err(Error::EncoderInitFailure(res));
     }
     let raw_self = ManuallyDrop::new(self);
-    Ok(InitializedEncoder(raw_self.0))
+    Ok(InitializedEncoder {
+      raw: raw_self.0,
+      sink: None,
+    })
+  }
+
+  // Encode into any writer, the metadata is not rewritten when finishing
+  pub fn init_write<W: Write>(self, writer: &mut W) -> Result<InitializedEncoder<'_>> {
+    let mut sink = Box::new(Sink {
+      writer,
+      error: None,
+    });
+    let res = unsafe {
+      FLAC__stream_encoder_init_stream(
+        self.0.as_ptr(),
+        Some(write_callback),
+        None,
+        None,
+        None,
+        &mut *sink as *mut Sink as *mut c_void,
+      )
+    };
+    if res != FLAC__StreamEncoderInitStatus_FLAC__STREAM_ENCODER_INIT_STATUS_OK {
+      return Err(Error::EncoderInitFailure(res));
+    }
+    let raw_self = ManuallyDrop::new(self);
+    Ok(InitializedEncoder {
+      raw: raw_self.0,
+      sink: Some(sink),
+    })
+  }
+}
+
+struct Sink<'a> {
+  writer: &'a mut dyn Write,
+  error: Option<io::Error>,
+}
+
+unsafe extern "C" fn write_callback(
+  _encoder: *const FLAC__StreamEncoder,
+  buffer: *const FLAC__byte,
+  bytes: usize,
+  _samples: u32,
+  _current_frame: u32,
+  client_data: *mut c_void,
+) -> FLAC__StreamEncoderWriteStatus {
+  let sink = &mut *(client_data as *mut Sink);
+  let buffer = slice::from_raw_parts(buffer, bytes);
+
+  match sink.writer.write_all(buffer) {
+    Ok(()) => FLAC__StreamEncoderWriteStatus_FLAC__STREAM_ENCODER_WRITE_STATUS_OK,
+    Err(err) => {
+      sink.error = Some(err);
+      FLAC__StreamEncoderWriteStatus_FLAC__STREAM_ENCODER_WRITE_STATUS_FATAL_ERROR
+    }
   }
 }
 
@@ -64,11 +136,15 @@ impl Default for Encoder {
   }
 }
 
-pub struct InitializedEncoder(NonNull<FLAC__StreamEncoder>);
+pub struct InitializedEncoder<'a> {
+  raw: NonNull<FLAC__StreamEncoder>,
+  // boxed so the address given to the callback stays the same
+  sink: Option<Box<Sink<'a>>>,
+}
 
-impl Drop for InitializedEncoder {
+impl Drop for InitializedEncoder<'_> {
   fn drop(&mut self) {
-    unsafe { FLAC__stream_encoder_delete(self.0.as_ptr()) }
+    unsafe { FLAC__stream_encoder_delete(self.raw.as_ptr()) }
   }
 }
 
@@ -82,31 +158,39 @@ impl Sample for i16 {
   }
 }
 
-impl InitializedEncoder {
-  pub fn process_interleaved(self, buffer: &[impl Sample + Clone]) -> Result<Self> {
-    let channels = unsafe { FLAC__stream_encoder_get_channels(self.0.as_ptr()) };
+impl InitializedEncoder<'_> {
+  fn error(&mut self) -> Error {
+    match self.sink.as_mut().and_then(|sink| sink.error.take()) {
+      Some(err) => Error::Io(err),
+      None => Error::EncodeFailure,
+    }
+  }
+
+  pub fn process_interleaved(mut self, buffer: &[impl Sample + Clone]) -> Result<Self> {
+    let channels = unsafe { FLAC__stream_encoder_get_channels(self.raw.as_ptr()) };
     let data: Vec<_> = buffer.iter().map(|v| v.to_owned().to_i32()).collect();
     let res = unsafe {
       FLAC__stream_encoder_process_interleaved(
-        self.0.as_ptr(),
+        self.raw.as_ptr(),
         data.as_ptr(),
         data.len() as u32 / channels,
       )
     };
     if res == 0 {
-      Err(Error::EncodeFailure)
+      Err(self.error())
     } else {
       Ok(self)
     }
   }
 
-  pub fn finish(self) -> Result<Encoder> {
-    let res = unsafe { FLAC__stream_encoder_finish(self.0.as_ptr()) };
+  pub fn finish(mut self) -> Result<Encoder> {
+    let res = unsafe { FLAC__stream_encoder_finish(self.raw.as_ptr()) };
     if res == 0 {
-      Err(Error::EncodeFailure)
+      Err(self.error())
     } else {
-      let raw_self = ManuallyDrop::new(self);
-      Ok(Encoder(raw_self.0))
+      let mut raw_self = ManuallyDrop::new(self);
+      drop(raw_self.sink.take());
+      Ok(Encoder(raw_self.raw))
     }
   }
 }
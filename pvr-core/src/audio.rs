@@ -0,0 +1,421 @@
+use std::{borrow::Cow, path::PathBuf};
+
+use anyhow::anyhow;
+use ndarray::prelude::*;
+use rubato::{FftFixedInOut, Resampler};
+
+use crate::{
+  error::{ensure_as, Error, Result},
+  progress::{Progress, ProgressObserver, Stage},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChannelLayout {
+  Mono,
+  Stereo,
+  // channels without a known speaker position
+  Discrete(usize),
+}
+
+impl ChannelLayout {
+  pub fn from_channels(channels: usize) -> Self {
+    match channels {
+      1 => Self::Mono,
+      2 => Self::Stereo,
+      n => Self::Discrete(n),
+    }
+  }
+
+  pub fn channels(&self) -> usize {
+    match self {
+      Self::Mono => 1,
+      Self::Stereo => 2,
+      Self::Discrete(n) => *n,
+    }
+  }
+}
+
+// Where the audio comes from, kept along with the separated stems
+#[derive(Clone, Debug, Default)]
+pub struct AudioMetadata {
+  pub source: Option<PathBuf>,
+  pub codec: Option<String>,
+  pub tags: Vec<(String, String)>,
+}
+
+// Samples of shape [channels, frames]
+#[derive(Clone, Debug)]
+pub struct AudioBuffer {
+  samples: Array2<f64>,
+  sample_rate: u32,
+  layout: ChannelLayout,
+  metadata: AudioMetadata,
+}
+
+impl AudioBuffer {
+  pub fn new(samples: Array2<f64>, sample_rate: u32) -> Result<Self> {
+    ensure_as!(Audio, sample_rate > 0, "Invalid sample rate {sample_rate}");
+    ensure_as!(Audio, samples.nrows() > 0, "The audio has no channel");
+
+    Ok(Self {
+      layout: ChannelLayout::from_channels(samples.nrows()),
+      samples,
+      sample_rate,
+      metadata: AudioMetadata::default(),
+    })
+  }
+
+  pub fn with_metadata(mut self, metadata: AudioMetadata) -> Self {
+    self.metadata = metadata;
+    self
+  }
+
+  pub fn samples(&self) -> ArrayView2<f64> {
+    self.samples.view()
+  }
+
+  pub fn into_samples(self) -> Array2<f64> {
+    self.samples
+  }
+
+  pub fn sample_rate(&self) -> u32 {
+    self.sample_rate
+  }
+
+  pub fn layout(&self) -> ChannelLayout {
+    self.layout
+  }
+
+  pub fn channels(&self) -> usize {
+    self.samples.nrows()
+  }
+
+  pub fn frames(&self) -> usize {
+    self.samples.ncols()
+  }
+
+  pub fn metadata(&self) -> &AudioMetadata {
+    &self.metadata
+  }
+
+  // Mono is duplicated to every channel, and down-mixing to mono averages
+  // the channels, other conversions keep the leading channels
+  pub fn to_layout(&self, layout: ChannelLayout) -> Result<Self> {
+    let channels = layout.channels();
+
+    let samples = match (self.layout, layout) {
+      (from, to) if from == to => self.samples.clone(),
+      (ChannelLayout::Mono, _) => self
+        .samples
+        .broadcast((channels, self.frames()))
+        .expect("Mono is broadcastable")
+        .to_owned(),
+      (_, ChannelLayout::Mono) => self
+        .samples
+        .mean_axis(Axis(0))
+        .unwrap()
+        .insert_axis(Axis(0)),
+      (from, _) if from.channels() >= channels => self.samples.slice(s![..channels, ..]).to_owned(),
+      (from, to) => {
+        return Err(Error::Audio(anyhow!(
+          "Can not convert {from:?} audio to {to:?}"
+        )))
+      }
+    };
+
+    Ok(Self {
+      samples,
+      sample_rate: self.sample_rate,
+      layout,
+      metadata: self.metadata.clone(),
+    })
+  }
+
+  pub fn resample(&self, sample_rate: u32, progress: &dyn ProgressObserver) -> Result<Self> {
+    ensure_as!(Audio, sample_rate > 0, "Invalid sample rate {sample_rate}");
+
+    let samples = if sample_rate == self.sample_rate {
+      self.samples.clone()
+    } else {
+      resample(self.samples.view(), self.sample_rate, sample_rate, progress)?
+    };
+
+    Ok(Self {
+      samples,
+      sample_rate,
+      layout: self.layout,
+      metadata: self.metadata.clone(),
+    })
+  }
+
+  // Borrows the audio if it already has the rate and layout
+  pub fn conform(
+    &self,
+    sample_rate: u32,
+    layout: ChannelLayout,
+    progress: &dyn ProgressObserver,
+  ) -> Result<Cow<'_, Self>> {
+    let mut res = Cow::Borrowed(self);
+
+    if res.layout != layout {
+      res = Cow::Owned(res.to_layout(layout)?);
+    }
+
+    if res.sample_rate != sample_rate {
+      res = Cow::Owned(res.resample(sample_rate, progress)?);
+    }
+
+    Ok(res)
+  }
+}
+
+#[tracing::instrument(skip_all)]
+pub fn resample(
+  samples: ArrayView2<f64>,
+  original_sample_rate: u32,
+  target_sample_rate: u32,
+  progress: &dyn ProgressObserver,
+) -> Result<Array2<f64>> {
+  resample_inner(samples, original_sample_rate, target_sample_rate, progress).map_err(Error::Dsp)
+}
+
+fn resample_inner(
+  samples: ArrayView2<f64>,
+  original_sample_rate: u32,
+  target_sample_rate: u32,
+  progress: &dyn ProgressObserver,
+) -> anyhow::Result<Array2<f64>> {
+  tracing::info!(
+    from = original_sample_rate,
+    to = target_sample_rate,
+    "Start resampling..."
+  );
+
+  let (channels, nbr_input_frames) = samples.dim();
+
+  let f_ratio = target_sample_rate as f64 / original_sample_rate as f64;
+
+  let mut outdata =
+    vec![Vec::with_capacity((nbr_input_frames as f64 * f_ratio) as usize); channels];
+
+  let mut resampler = FftFixedInOut::<f64>::new(
+    original_sample_rate as usize,
+    target_sample_rate as usize,
+    1024, // TODO: maybe adjust this?
+    channels,
+  )?;
+
+  let samples: Vec<Vec<f64>> = samples.outer_iter().map(|c| c.to_vec()).collect();
+
+  let mut input_frames_next = resampler.input_frames_next();
+  let mut outbuffer = vec![vec![0.0; resampler.output_frames_max()]; channels];
+  let mut indata_slices: Vec<&[f64]> = samples.iter().map(|v| &v[..]).collect();
+
+  let total = Some(nbr_input_frames as u64);
+  let report = |remaining: usize| {
+    let done = (nbr_input_frames - remaining) as u64;
+    progress.on_progress(Progress::new(Stage::Resample, done, total));
+  };
+  report(nbr_input_frames);
+
+  fn append_frames(buffers: &mut [Vec<f64>], additional: &[Vec<f64>], nbr_frames: usize) {
+    buffers
+      .iter_mut()
+      .zip(additional.iter())
+      .for_each(|(b, a)| b.extend_from_slice(&a[..nbr_frames]));
+  }
+
+  while indata_slices[0].len() >= input_frames_next {
+    let (nbr_in, nbr_out) = resampler.process_into_buffer(&indata_slices, &mut outbuffer, None)?;
+
+    for chan in indata_slices.iter_mut() {
+      *chan = &chan[nbr_in..];
+    }
+
+    append_frames(&mut outdata, &outbuffer, nbr_out);
+    input_frames_next = resampler.input_frames_next();
+    report(indata_slices[0].len());
+  }
+
+  if !indata_slices[0].is_empty() {
+    let (_, nbr_out) =
+      resampler.process_partial_into_buffer(Some(&indata_slices), &mut outbuffer, None)?;
+
+    append_frames(&mut outdata, &outbuffer, nbr_out);
+    report(0);
+  }
+
+  let length = outdata[0].len();
+  tracing::info!(samples = length, "Audio resampled");
+
+  Ok(Array2::from_shape_vec(
+    (channels, length),
+    outdata.into_iter().flatten().collect(),
+  )?)
+}
+
+// Resamples the audio fed block by block, the result is the same as `resample`.
+// The audio is passed through if the rates are the same
+pub struct StreamResampler {
+  resampler: Option<FftFixedInOut<f64>>,
+  channels: usize,
+  // input frames which are not enough for the next round yet
+  pending: Vec<Vec<f64>>,
+  buffer: Vec<Vec<f64>>,
+}
+
+fn to_samples(channels: Vec<Vec<f64>>) -> Result<Array2<f64>> {
+  let shape = (channels.len(), channels.first().map_or(0, Vec::len));
+  Array2::from_shape_vec(shape, channels.into_iter().flatten().collect())
+    .map_err(|err| Error::Dsp(err.into()))
+}
+
+impl StreamResampler {
+  pub fn new(original_sample_rate: u32, target_sample_rate: u32, channels: usize) -> Result<Self> {
+    ensure_as!(
+      Audio,
+      original_sample_rate > 0 && target_sample_rate > 0,
+      "Invalid sample rate {original_sample_rate} -> {target_sample_rate}"
+    );
+    ensure_as!(Audio, channels > 0, "The audio has no channel");
+
+    let resampler = if original_sample_rate == target_sample_rate {
+      None
+    } else {
+      Some(
+        FftFixedInOut::<f64>::new(
+          original_sample_rate as usize,
+          target_sample_rate as usize,
+          1024,
+          channels,
+        )
+        .map_err(|err| Error::Dsp(err.into()))?,
+      )
+    };
+
+    let buffer = match &resampler {
+      Some(resampler) => vec![vec![0.0; resampler.output_frames_max()]; channels],
+      None => Vec::new(),
+    };
+
+    Ok(Self {
+      resampler,
+      channels,
+      pending: vec![Vec::new(); channels],
+      buffer,
+    })
+  }
+
+  // Returns the frames resampled so far, which lag behind the input by up to
+  // one round of the resampler
+  pub fn push(&mut self, block: ArrayView2<f64>) -> Result<Array2<f64>> {
+    ensure_as!(
+      Audio,
+      block.nrows() == self.channels,
+      "Expected {} channels, got {}",
+      self.channels,
+      block.nrows()
+    );
+
+    let Some(resampler) = &mut self.resampler else {
+      return Ok(block.to_owned());
+    };
+
+    for (pending, row) in self.pending.iter_mut().zip(block.outer_iter()) {
+      pending.extend(row.iter());
+    }
+
+    let mut res = vec![Vec::new(); self.channels];
+    let mut consumed = 0;
+
+    while self.pending[0].len() - consumed >= resampler.input_frames_next() {
+      let input: Vec<&[f64]> = self.pending.iter().map(|p| &p[consumed..]).collect();
+      let (nbr_in, nbr_out) = resampler
+        .process_into_buffer(&input, &mut self.buffer, None)
+        .map_err(|err| Error::Dsp(err.into()))?;
+
+      consumed += nbr_in;
+      for (res, out) in res.iter_mut().zip(&self.buffer) {
+        res.extend_from_slice(&out[..nbr_out]);
+      }
+    }
+
+    for pending in &mut self.pending {
+      pending.drain(..consumed);
+    }
+
+    to_samples(res)
+  }
+
+  // Resamples the frames left
+  pub fn finish(mut self) -> Result<Array2<f64>> {
+    let Some(resampler) = &mut self.resampler else {
+      return Ok(Array2::zeros((self.channels, 0)));
+    };
+
+    if self.pending[0].is_empty() {
+      return Ok(Array2::zeros((self.channels, 0)));
+    }
+
+    let (_, nbr_out) = resampler
+      .process_partial_into_buffer(Some(self.pending.as_slice()), &mut self.buffer, None)
+      .map_err(|err| Error::Dsp(err.into()))?;
+
+    to_samples(
+      self
+        .buffer
+        .iter()
+        .map(|out| out[..nbr_out].to_vec())
+        .collect(),
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn stream_resampler_matches_resample() {
+    let input = Array2::from_shape_fn((2, 20000), |(row, i)| ((i + row * 13) as f64 * 0.05).sin());
+    let expected = resample(input.view(), 44100, 48000, &()).unwrap();
+
+    // blocks shorter and longer than a round of the resampler, and empty ones
+    let mut resampler = StreamResampler::new(44100, 48000, 2).unwrap();
+    let mut res = Array2::zeros((2, 0));
+    let (mut pos, mut sizes) = (0, [0, 1, 1000, 0, 4095, 17, 3333].iter().cycle());
+    while pos < input.ncols() {
+      let end = (pos + sizes.next().unwrap()).min(input.ncols());
+      let block = resampler.push(input.slice(s![.., pos..end])).unwrap();
+      res.append(Axis(1), block.view()).unwrap();
+      pos = end;
+    }
+    let tail = resampler.finish().unwrap();
+    res.append(Axis(1), tail.view()).unwrap();
+
+    assert_eq!(res, expected);
+  }
+
+  #[test]
+  fn mono_to_stereo_duplicates_the_channel() {
+    let mono = AudioBuffer::new(array![[0.5, -0.25, 1.0]], 44100).unwrap();
+    let stereo = mono.to_layout(ChannelLayout::Stereo).unwrap();
+
+    assert_eq!(stereo.channels(), 2);
+    assert_eq!(stereo.layout(), ChannelLayout::Stereo);
+    assert_eq!(
+      stereo.samples(),
+      array![[0.5, -0.25, 1.0], [0.5, -0.25, 1.0]]
+    );
+  }
+
+  #[test]
+  fn stereo_to_mono_averages_the_channels() {
+    let stereo = AudioBuffer::new(array![[0.5, -0.25, 1.0], [0.25, 0.25, -1.0]], 44100).unwrap();
+    let mono = stereo.to_layout(ChannelLayout::Mono).unwrap();
+
+    assert_eq!(mono.channels(), 1);
+    assert_eq!(mono.layout(), ChannelLayout::Mono);
+    assert_eq!(mono.samples(), array![[0.375, 0.0, 0.0]]);
+  }
+}
@@ -0,0 +1,48 @@
+use std::{
+  error::Error,
+  fmt,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+  },
+};
+
+// Shared by every clone, checked by the separators between chunks
+// and by the writers between blocks
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn cancel(&self) {
+    self.0.store(true, Ordering::Relaxed);
+  }
+
+  pub fn is_cancelled(&self) -> bool {
+    self.0.load(Ordering::Relaxed)
+  }
+
+  // Fails with `Cancelled` once cancelled
+  pub fn check(&self) -> Result<(), Cancelled> {
+    if self.is_cancelled() {
+      Err(Cancelled)
+    } else {
+      Ok(())
+    }
+  }
+}
+
+// The error of a cancelled job, which is `Error::Cancelled` in the results of pvr-core
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Cancelled")
+  }
+}
+
+impl Error for Cancelled {}
@@ -1,29 +1,348 @@
-use anyhow::Result;
+use std::{fmt, panic, path::PathBuf, sync::OnceLock};
+
+use anyhow::{anyhow, bail, ensure, Context};
 use ort::{
-  CPUExecutionProvider, CUDAExecutionProvider, DirectMLExecutionProvider,
-  ExecutionProviderDispatch, TensorRTExecutionProvider,
+  CPUExecutionProvider, CUDAExecutionProvider, DirectMLExecutionProvider, ExecutionProvider,
+  GraphOptimizationLevel, OneDNNExecutionProvider, Session, SessionBuilder,
+  TensorRTExecutionProvider, XNNPACKExecutionProvider,
 };
 
+use crate::error::{Error, Result};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Backend {
   DirectML,
   CUDA,
   TensorRT,
+  XNNPACK,
+  OneDNN,
   CPU,
+  // pure Rust inference without ONNX Runtime, only available
+  // with the `tract` feature
+  Tract,
+}
+
+impl fmt::Display for Backend {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::DirectML => write!(f, "DirectML"),
+      Self::CUDA => write!(f, "CUDA"),
+      Self::TensorRT => write!(f, "TensorRT"),
+      Self::XNNPACK => write!(f, "XNNPACK"),
+      Self::OneDNN => write!(f, "oneDNN"),
+      Self::CPU => write!(f, "CPU"),
+      Self::Tract => write!(f, "tract"),
+    }
+  }
 }
 
 impl Backend {
-  fn to_ep(&self) -> ExecutionProviderDispatch {
+  pub const ALL: &'static [Backend] = &[
+    Self::DirectML,
+    Self::CUDA,
+    Self::TensorRT,
+    Self::XNNPACK,
+    Self::OneDNN,
+    Self::CPU,
+    Self::Tract,
+  ];
+
+  // The name used by ONNX Runtime
+  pub fn provider_name(&self) -> &'static str {
+    match self {
+      Self::DirectML => "DmlExecutionProvider",
+      Self::CUDA => "CUDAExecutionProvider",
+      Self::TensorRT => "TensorrtExecutionProvider",
+      Self::XNNPACK => "XnnpackExecutionProvider",
+      Self::OneDNN => "DnnlExecutionProvider",
+      Self::CPU => "CPUExecutionProvider",
+      // never reported by ONNX Runtime
+      Self::Tract => "Tract",
+    }
+  }
+
+  pub fn from_provider_name(name: &str) -> Option<Self> {
+    Self::ALL
+      .iter()
+      .copied()
+      .find(|b| b.is_onnxruntime() && b.provider_name() == name)
+  }
+
+  // Whether the backend is an execution provider of ONNX Runtime
+  pub fn is_onnxruntime(&self) -> bool {
+    !matches!(self, Self::Tract)
+  }
+
+  // Whether the loaded ONNX Runtime is built with this provider,
+  // none of them is if ONNX Runtime can not be loaded
+  pub fn is_available(&self) -> bool {
+    let available = match self {
+      Self::Tract => return cfg!(feature = "tract"),
+      _ if !runtime_available() => return false,
+      Self::DirectML => DirectMLExecutionProvider::default().is_available(),
+      Self::CUDA => CUDAExecutionProvider::default().is_available(),
+      Self::TensorRT => TensorRTExecutionProvider::default().is_available(),
+      Self::XNNPACK => XNNPACKExecutionProvider::default().is_available(),
+      Self::OneDNN => OneDNNExecutionProvider::default().is_available(),
+      Self::CPU => CPUExecutionProvider::default().is_available(),
+    };
+
+    available.unwrap_or(false)
+  }
+
+  fn register(&self, builder: &SessionBuilder, memory_arena: bool) -> anyhow::Result<()> {
     match self {
-      Self::DirectML => DirectMLExecutionProvider::default().build(),
-      Self::CUDA => CUDAExecutionProvider::default().build(),
-      Self::TensorRT => TensorRTExecutionProvider::default().build(),
-      Self::CPU => CPUExecutionProvider::default().build(),
+      Self::DirectML => DirectMLExecutionProvider::default().register(builder)?,
+      Self::CUDA => CUDAExecutionProvider::default().register(builder)?,
+      Self::TensorRT => TensorRTExecutionProvider::default().register(builder)?,
+      Self::XNNPACK => XNNPACKExecutionProvider::default().register(builder)?,
+      Self::OneDNN => OneDNNExecutionProvider::default().register(builder)?,
+      Self::CPU => CPUExecutionProvider::default()
+        .with_arena_allocator(memory_arena)
+        .register(builder)?,
+      Self::Tract => bail!("tract is not an execution provider"),
     }
+
+    Ok(())
   }
 }
 
+// Whether the ONNX Runtime library can be loaded, it is loaded by the first call.
+// ort panics if the library is missing, so the panic is caught and kept quiet
+pub fn runtime_available() -> bool {
+  static LOADED: OnceLock<bool> = OnceLock::new();
+
+  *LOADED.get_or_init(|| {
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+    let loaded = panic::catch_unwind(|| ort::init().commit());
+    panic::set_hook(hook);
+
+    let loaded = matches!(loaded, Ok(Ok(_)));
+    if !loaded {
+      tracing::warn!("Failed to load ONNX Runtime");
+    }
+    loaded
+  })
+}
+
+static DEFAULT_BACKENDS: OnceLock<Vec<Backend>> = OnceLock::new();
+
+// Register the backends in order, the ones that fail are skipped.
+// Returns the first registered backend. ONNX Runtime offers the nodes to the
+// registered providers in order, so which one runs a node is only known to it
+fn register_backends(
+  builder: &SessionBuilder,
+  backends: &[Backend],
+  memory_arena: bool,
+) -> Backend {
+  let mut registered = Vec::new();
+
+  for &backend in backends {
+    if !backend.is_onnxruntime() {
+      tracing::warn!(%backend, "The backend only works as the first choice");
+      continue;
+    }
+
+    if !backend.is_available() {
+      tracing::warn!(%backend, "The backend is not available in the loaded ONNX Runtime");
+      continue;
+    }
+
+    match backend.register(builder, memory_arena) {
+      Ok(()) => registered.push(backend),
+      Err(err) => tracing::warn!(%backend, %err, "Failed to register the backend"),
+    }
+  }
+
+  // ONNX Runtime always falls back to its CPU provider
+  let first = registered.first().copied().unwrap_or(Backend::CPU);
+
+  if backends.first().is_some_and(|&b| b != first) {
+    tracing::warn!(providers = ?registered, "The first choice is not registered");
+  } else {
+    tracing::info!(providers = ?registered, "Registered providers");
+  }
+
+  first
+}
+
+fn default_backends() -> &'static [Backend] {
+  DEFAULT_BACKENDS.get().map(Vec::as_slice).unwrap_or(&[])
+}
+
+// The default execution providers of every session, a session
+// built with its own backends will not use them.
+// ONNX Runtime is loaded by the first session using it
 pub fn setup_backends(backends: impl AsRef<[Backend]>) -> Result<()> {
-  let backends: Vec<_> = backends.as_ref().into_iter().map(|b| b.to_ep()).collect();
-  ort::init().with_execution_providers(backends).commit()?;
-  Ok(())
+  DEFAULT_BACKENDS
+    .set(backends.as_ref().to_vec())
+    .map_err(|_| Error::Config(anyhow!("The default backends are already set")))
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OptimizationLevel {
+  Disable,
+  Level1,
+  Level2,
+  #[default]
+  Level3,
+}
+
+impl OptimizationLevel {
+  fn to_ort(self) -> GraphOptimizationLevel {
+    match self {
+      Self::Disable => GraphOptimizationLevel::Disable,
+      Self::Level1 => GraphOptimizationLevel::Level1,
+      Self::Level2 => GraphOptimizationLevel::Level2,
+      Self::Level3 => GraphOptimizationLevel::Level3,
+    }
+  }
+}
+
+// Options of an ONNX Runtime session, the defaults are the same as ONNX Runtime,
+// except the graph optimization level
+#[derive(Clone, Debug)]
+pub struct SessionOptions {
+  backends: Option<Vec<Backend>>,
+  intra_threads: Option<usize>,
+  inter_threads: Option<usize>,
+  memory_arena: bool,
+  memory_pattern: bool,
+  optimization_level: OptimizationLevel,
+  optimized_model_path: Option<PathBuf>,
+  profiling_path: Option<PathBuf>,
+}
+
+impl Default for SessionOptions {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl SessionOptions {
+  pub const fn new() -> Self {
+    Self {
+      backends: None,
+      intra_threads: None,
+      inter_threads: None,
+      memory_arena: true,
+      memory_pattern: true,
+      optimization_level: OptimizationLevel::Level3,
+      optimized_model_path: None,
+      profiling_path: None,
+    }
+  }
+
+  // Execution providers of this session only, in order of preference,
+  // the ones set by `setup_backends` are used if not given
+  pub fn with_backends(mut self, backends: impl Into<Vec<Backend>>) -> Self {
+    self.backends = Some(backends.into());
+    self
+  }
+
+  // Threads used to parallelize the execution within nodes
+  pub fn with_intra_threads(mut self, threads: usize) -> Self {
+    self.intra_threads = Some(threads);
+    self
+  }
+
+  // Threads used to parallelize the execution of the graph
+  pub fn with_inter_threads(mut self, threads: usize) -> Self {
+    self.inter_threads = Some(threads);
+    self
+  }
+
+  pub fn with_memory_arena(mut self, enable: bool) -> Self {
+    self.memory_arena = enable;
+    self
+  }
+
+  pub fn with_memory_pattern(mut self, enable: bool) -> Self {
+    self.memory_pattern = enable;
+    self
+  }
+
+  pub fn with_optimization_level(mut self, level: OptimizationLevel) -> Self {
+    self.optimization_level = level;
+    self
+  }
+
+  // Save the model after graph optimization
+  pub fn with_optimized_model_path(mut self, path: impl Into<PathBuf>) -> Self {
+    self.optimized_model_path = Some(path.into());
+    self
+  }
+
+  // Write the profiling result of ONNX Runtime into a JSON file. The path is
+  // a prefix, ONNX Runtime appends the start time and `.json` to it
+  pub fn with_profiling(mut self, path: impl Into<PathBuf>) -> Self {
+    self.profiling_path = Some(path.into());
+    self
+  }
+
+  // The backends in order of preference
+  pub(crate) fn backends(&self) -> &[Backend] {
+    self.backends.as_deref().unwrap_or(default_backends())
+  }
+
+  // Returns the session and its first registered backend, `model` is the content of the ONNX model
+  pub(crate) fn commit(&self, model: &[u8]) -> Result<(Session, Backend)> {
+    let (builder, backend) = self.builder().map_err(Error::BackendInit)?;
+
+    // the graph is only checked by ONNX Runtime once it is loaded
+    let session = builder
+      .commit_from_memory(model)
+      .context("Failed to load onnx model")
+      .map_err(Error::InvalidModel)?;
+
+    Ok((session, backend))
+  }
+
+  fn builder(&self) -> anyhow::Result<(SessionBuilder, Backend)> {
+    ensure!(runtime_available(), "ONNX Runtime can not be loaded");
+
+    let mut builder = Session::builder()
+      .context("Failed to get ort session builder")?
+      .with_optimization_level(self.optimization_level.to_ort())
+      .context("Failed to optimize ort session")?
+      .with_memory_pattern(self.memory_pattern)
+      .context("Failed to set memory pattern")?;
+
+    if let Some(threads) = self.intra_threads {
+      builder = builder
+        .with_intra_threads(threads)
+        .context("Failed to set intra-op threads")?;
+    }
+
+    if let Some(threads) = self.inter_threads {
+      builder = builder
+        .with_parallel_execution(true)
+        .context("Failed to enable parallel execution")?
+        .with_inter_threads(threads)
+        .context("Failed to set inter-op threads")?;
+    }
+
+    if let Some(path) = &self.optimized_model_path {
+      builder = builder
+        .with_optimized_model_path(path)
+        .context("Failed to set optimized model path")?;
+    }
+
+    if let Some(path) = &self.profiling_path {
+      tracing::info!(?path, "Profiling enabled");
+      builder = builder
+        .with_profiling(path)
+        .context("Failed to enable profiling")?;
+    }
+
+    let mut backends = self.backends().to_vec();
+    // the arena is an option of the CPU provider, which is implicit otherwise
+    if !self.memory_arena && !backends.contains(&Backend::CPU) {
+      backends.push(Backend::CPU);
+    }
+    let backend = register_backends(&builder, &backends, self.memory_arena);
+
+    Ok((builder, backend))
+  }
 }
@@ -0,0 +1,5 @@
+mod stft;
+mod window;
+
+pub use stft::{PadMode, Stft, StftConfig, StftScratch};
+pub use window::Window;
@@ -0,0 +1,595 @@
+use std::{sync::Arc, thread};
+
+use ndarray::{prelude::*, Zip};
+use realfft::{num_complex::Complex, ComplexToReal, RealFftPlanner, RealToComplex};
+
+use super::Window;
+use crate::error::{ensure_as, Error, Result};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PadMode {
+  #[default]
+  Reflect,
+  // pad with zeros
+  Constant,
+  Replicate,
+}
+
+#[derive(Clone, Debug)]
+pub struct StftConfig {
+  n_fft: usize,
+  hop_length: usize,
+  window: Window,
+  periodic: bool,
+  center: bool,
+  pad_mode: PadMode,
+  onesided: bool,
+  threads: usize,
+}
+
+// Defaults are the same as `torch.stft` with a periodic hann window
+impl StftConfig {
+  pub fn new(n_fft: usize, hop_length: usize) -> Self {
+    Self {
+      n_fft,
+      hop_length,
+      window: Window::Hann,
+      periodic: true,
+      center: true,
+      pad_mode: PadMode::Reflect,
+      onesided: true,
+      threads: 1,
+    }
+  }
+
+  pub fn with_window(mut self, window: Window) -> Self {
+    self.window = window;
+    self
+  }
+
+  pub fn with_periodic(mut self, periodic: bool) -> Self {
+    self.periodic = periodic;
+    self
+  }
+
+  pub fn with_center(mut self, center: bool) -> Self {
+    self.center = center;
+    self
+  }
+
+  pub fn with_pad_mode(mut self, pad_mode: PadMode) -> Self {
+    self.pad_mode = pad_mode;
+    self
+  }
+
+  pub fn with_onesided(mut self, onesided: bool) -> Self {
+    self.onesided = onesided;
+    self
+  }
+
+  // Spread the rows, and the frames or samples of each row, over threads
+  pub fn with_threads(mut self, threads: usize) -> Self {
+    self.threads = threads.max(1);
+    self
+  }
+
+  pub fn build(self) -> Result<Stft> {
+    ensure_as!(Config, self.n_fft > 0, "`n_fft` should be positive");
+    ensure_as!(
+      Config,
+      self.hop_length > 0 && self.hop_length <= self.n_fft,
+      "Invalid hop length {} for `n_fft` {}",
+      self.hop_length,
+      self.n_fft
+    );
+
+    let mut planner = RealFftPlanner::<f64>::new();
+
+    Ok(Stft {
+      window: self.window.build(self.n_fft, self.periodic),
+      forward: planner.plan_fft_forward(self.n_fft),
+      backward: planner.plan_fft_inverse(self.n_fft),
+      config: self,
+    })
+  }
+}
+
+// The threshold `torch.istft` checks the window envelope against. torch fails
+// when the envelope of any sample is below it, here those samples are zeroed
+const ENVELOPE_EPS: f64 = 1e-11;
+
+// Buffers used by a single thread
+struct Worker {
+  frame: Vec<f64>,
+  spectrum: Vec<Complex<f64>>,
+  scratch: Vec<Complex<f64>>,
+}
+
+pub struct StftScratch {
+  workers: Vec<Worker>,
+  // the sum of squared windows only depends on the frames and output length
+  divider: Array1<f64>,
+  divider_frames: usize,
+}
+
+// Reflect padding without repeating the edge, same as `torch.stft`
+fn reflect(pos: isize, length: usize) -> usize {
+  let last = length as isize - 1;
+  let pos = pos.abs();
+  (if pos > last { 2 * last - pos } else { pos }) as usize
+}
+
+fn run_jobs<T: Send>(
+  jobs: Vec<T>,
+  workers: &mut [Worker],
+  f: impl Fn(T, &mut Worker) -> anyhow::Result<()> + Sync,
+) -> anyhow::Result<()> {
+  if workers.len() == 1 {
+    return jobs.into_iter().try_for_each(|job| f(job, &mut workers[0]));
+  }
+
+  let mut groups: Vec<Vec<T>> = workers.iter().map(|_| Vec::new()).collect();
+  for (i, job) in jobs.into_iter().enumerate() {
+    groups[i % workers.len()].push(job);
+  }
+
+  let f = &f;
+
+  thread::scope(|s| {
+    let handles: Vec<_> = groups
+      .into_iter()
+      .zip(workers.iter_mut())
+      .map(|(group, worker)| s.spawn(move || group.into_iter().try_for_each(|job| f(job, worker))))
+      .collect();
+
+    handles
+      .into_iter()
+      .try_for_each(|h| h.join().expect("STFT worker panicked"))
+  })
+}
+
+pub struct Stft {
+  config: StftConfig,
+  window: Array1<f64>,
+  forward: Arc<dyn RealToComplex<f64>>,
+  backward: Arc<dyn ComplexToReal<f64>>,
+}
+
+impl Stft {
+  pub fn n_fft(&self) -> usize {
+    self.config.n_fft
+  }
+
+  pub fn hop_length(&self) -> usize {
+    self.config.hop_length
+  }
+
+  pub fn freq_num(&self) -> usize {
+    if self.config.onesided {
+      self.config.n_fft / 2 + 1
+    } else {
+      self.config.n_fft
+    }
+  }
+
+  pub fn frames(&self, length: usize) -> usize {
+    let StftConfig {
+      n_fft, hop_length, ..
+    } = self.config;
+
+    if self.config.center {
+      length / hop_length + 1
+    } else if length < n_fft {
+      0
+    } else {
+      (length - n_fft) / hop_length + 1
+    }
+  }
+
+  // Length of the signal covered by `frames`, same as `torch.istft` without `length`
+  pub fn default_length(&self, frames: usize) -> usize {
+    let StftConfig {
+      n_fft, hop_length, ..
+    } = self.config;
+
+    let length = n_fft + hop_length * frames.saturating_sub(1);
+    if self.config.center {
+      length - n_fft / 2 * 2
+    } else {
+      length
+    }
+  }
+
+  pub fn make_scratch(&self) -> StftScratch {
+    let scratch_len = self
+      .forward
+      .get_scratch_len()
+      .max(self.backward.get_scratch_len());
+
+    StftScratch {
+      workers: (0..self.config.threads)
+        .map(|_| Worker {
+          frame: self.forward.make_input_vec(),
+          spectrum: self.forward.make_output_vec(),
+          scratch: vec![Complex::default(); scratch_len],
+        })
+        .collect(),
+      divider: Array1::zeros(0),
+      divider_frames: 0,
+    }
+  }
+
+  // Offset of the first sample of a frame in the unpadded signal
+  fn frame_start(&self, frame_id: usize) -> isize {
+    let start = (frame_id * self.config.hop_length) as isize;
+    if self.config.center {
+      start - (self.config.n_fft / 2) as isize
+    } else {
+      start
+    }
+  }
+
+  // The part of a frame which lies inside the output
+  fn overlap(&self, frame_id: usize, length: usize) -> (usize, usize) {
+    let start = self.frame_start(frame_id);
+    let left = (-start).max(0) as usize;
+    let right = (length as isize - start).clamp(0, self.config.n_fft as isize) as usize;
+    (left, right.max(left))
+  }
+
+  fn load_frame(&self, input: ArrayView1<f64>, frame_id: usize, frame: &mut [f64]) {
+    let length = input.len();
+    let left = self.frame_start(frame_id);
+
+    if left >= 0 && left as usize + self.config.n_fft <= length {
+      let left = left as usize;
+      let samples = input.slice(s![left..(left + self.config.n_fft)]);
+      for ((a, &x), &w) in frame.iter_mut().zip(samples).zip(&self.window) {
+        *a = x * w;
+      }
+      return;
+    }
+
+    for (i, (a, &w)) in frame.iter_mut().zip(&self.window).enumerate() {
+      let pos = left + i as isize;
+      let x = if pos >= 0 && (pos as usize) < length {
+        input[pos as usize]
+      } else {
+        match self.config.pad_mode {
+          PadMode::Reflect => input[reflect(pos, length)],
+          PadMode::Constant => 0.0,
+          PadMode::Replicate => input[pos.clamp(0, length as isize - 1) as usize],
+        }
+      };
+      *a = x * w;
+    }
+  }
+
+  fn forward_frames(
+    &self,
+    input: ArrayView1<f64>,
+    mut output: ArrayViewMut3<f64>,
+    first_frame: usize,
+    worker: &mut Worker,
+  ) -> anyhow::Result<()> {
+    let n_fft = self.config.n_fft;
+    let half = n_fft / 2 + 1;
+
+    for (k, mut column) in output.axis_iter_mut(Axis(2)).enumerate() {
+      self.load_frame(input, first_frame + k, &mut worker.frame);
+
+      self.forward.process_with_scratch(
+        &mut worker.frame,
+        &mut worker.spectrum,
+        &mut worker.scratch,
+      )?;
+
+      for i in 0..column.len_of(Axis(1)) {
+        // the other half is the conjugate of the onesided result
+        let cur = if i < half {
+          worker.spectrum[i]
+        } else {
+          worker.spectrum[n_fft - i].conj()
+        };
+        column[[0, i]] = cur.re;
+        column[[1, i]] = cur.im;
+      }
+    }
+
+    Ok(())
+  }
+
+  // Reconstructs the part of the signal starting at `offset`. Only the frames
+  // covering it are run, and each sample sums the frames in order, so the result
+  // does not depend on how the signal is split
+  fn inverse_range(
+    &self,
+    input: ArrayView3<f64>,
+    mut output: ArrayViewMut1<f64>,
+    offset: usize,
+    divider: ArrayView1<f64>,
+    worker: &mut Worker,
+  ) -> anyhow::Result<()> {
+    let (_, freq_num, frame_num) = input.dim();
+    let (first, last) = (offset as isize, (offset + output.len()) as isize);
+    let n_fft = self.config.n_fft as isize;
+    let scale = self.config.n_fft as f64;
+
+    output.fill(0.0);
+
+    for frame_id in 0..frame_num {
+      let start = self.frame_start(frame_id);
+      if start + n_fft <= first || start >= last {
+        continue;
+      }
+
+      for (i, cur) in worker.spectrum.iter_mut().enumerate() {
+        *cur = if i < freq_num {
+          Complex::new(input[[0, i, frame_id]], input[[1, i, frame_id]])
+        } else {
+          Complex::default()
+        };
+      }
+
+      // TODO: check this
+      let last_bin = worker.spectrum.len() - 1;
+      worker.spectrum[0].im = 0.0;
+      worker.spectrum[last_bin].im = 0.0;
+
+      self.backward.process_with_scratch(
+        &mut worker.spectrum,
+        &mut worker.frame,
+        &mut worker.scratch,
+      )?;
+
+      let left = (first - start).max(0) as usize;
+      let right = (last - start).min(n_fft) as usize;
+
+      for i in left..right {
+        let pos = (start + i as isize - first) as usize;
+        output[pos] += worker.frame[i] * self.window[i] / scale;
+      }
+    }
+
+    // samples the frames barely cover can not be recovered, leave them as zero
+    // instead of amplifying the rounding error
+    Zip::from(&mut output).and(&divider).for_each(|a, &b| {
+      *a = if b > ENVELOPE_EPS { *a / b } else { 0.0 };
+    });
+
+    Ok(())
+  }
+
+  fn update_divider(&self, scratch: &mut StftScratch, frame_num: usize, length: usize) {
+    if scratch.divider.len() == length && scratch.divider_frames == frame_num {
+      return;
+    }
+
+    let mut divider = Array1::zeros(length);
+
+    for frame_id in 0..frame_num {
+      let start = self.frame_start(frame_id);
+      let (left, right) = self.overlap(frame_id, length);
+
+      for i in left..right {
+        let pos = (start + i as isize) as usize;
+        divider[pos] += self.window[i] * self.window[i];
+      }
+    }
+
+    scratch.divider = divider;
+    scratch.divider_frames = frame_num;
+  }
+
+  // [batch, t] -> [batch, (re, im), freq, frames]
+  // NOTE: the shape is different from `torch.stft`!
+  pub fn stft(&self, input: ArrayView2<f64>) -> Result<Array4<f64>> {
+    let (batch_num, length) = input.dim();
+    let mut res = Array4::zeros((batch_num, 2, self.freq_num(), self.frames(length)));
+    self.stft_into(input, res.view_mut(), &mut self.make_scratch())?;
+    Ok(res)
+  }
+
+  // Only the leading bins are computed if `output` has less bins than `freq_num`
+  pub fn stft_into(
+    &self,
+    input: ArrayView2<f64>,
+    mut output: ArrayViewMut4<f64>,
+    scratch: &mut StftScratch,
+  ) -> Result<()> {
+    let (batch_num, length) = input.dim();
+    let (out_batch, out_parts, freq_num, frame_num) = output.dim();
+
+    ensure_as!(
+      Dsp,
+      out_batch == batch_num && out_parts == 2 && frame_num == self.frames(length),
+      "Mismatched STFT output shape {:?}",
+      output.dim()
+    );
+    ensure_as!(
+      Dsp,
+      freq_num <= self.freq_num(),
+      "Too many frequency bins {freq_num}"
+    );
+    ensure_as!(Dsp, length > 0, "The input is empty");
+    if self.config.center && self.config.pad_mode == PadMode::Reflect {
+      ensure_as!(
+        Dsp,
+        length > self.config.n_fft / 2,
+        "The input is too short for reflect padding"
+      );
+    }
+
+    let splits = scratch.workers.len().div_ceil(batch_num).max(1);
+    let per_split = frame_num.div_ceil(splits).max(1);
+
+    let mut jobs = Vec::with_capacity(batch_num * splits);
+    for (row, mut rest) in input.outer_iter().zip(output.outer_iter_mut()) {
+      let mut first_frame = 0;
+      while first_frame < frame_num {
+        let take = per_split.min(frame_num - first_frame);
+        let (head, tail) = rest.split_at(Axis(2), take);
+        jobs.push((row, head, first_frame));
+        rest = tail;
+        first_frame += take;
+      }
+    }
+
+    run_jobs(
+      jobs,
+      &mut scratch.workers,
+      |(row, out, first_frame), worker| self.forward_frames(row, out, first_frame, worker),
+    )
+    .map_err(Error::Dsp)
+  }
+
+  // [batch, (re, im), freq, frames] -> [batch, length]
+  // the length defaults to `default_length`, like `torch.istft`
+  pub fn istft(&self, input: ArrayView4<f64>, length: Option<usize>) -> Result<Array2<f64>> {
+    let (batch_num, _, _, frame_num) = input.dim();
+    let length = length.unwrap_or_else(|| self.default_length(frame_num));
+    let mut res = Array2::zeros((batch_num, length));
+    self.istft_into(input, res.view_mut(), &mut self.make_scratch())?;
+    Ok(res)
+  }
+
+  // The output is exactly as long as `output`, missing bins are treated as zero,
+  // and only the first half of a twosided spectrum is used
+  pub fn istft_into(
+    &self,
+    input: ArrayView4<f64>,
+    mut output: ArrayViewMut2<f64>,
+    scratch: &mut StftScratch,
+  ) -> Result<()> {
+    let (batch_num, parts, freq_num, frame_num) = input.dim();
+    let (out_batch, length) = output.dim();
+
+    ensure_as!(
+      Dsp,
+      parts == 2,
+      "The input should have real and imaginary parts"
+    );
+    ensure_as!(
+      Dsp,
+      out_batch == batch_num,
+      "Mismatched iSTFT output shape {:?}",
+      output.dim()
+    );
+    ensure_as!(
+      Dsp,
+      freq_num <= self.freq_num(),
+      "Too many frequency bins {freq_num}"
+    );
+    ensure_as!(Dsp, frame_num > 0, "The input has no frame");
+
+    let input = input.slice_move(s![.., .., ..freq_num.min(self.config.n_fft / 2 + 1), ..]);
+
+    self.update_divider(scratch, frame_num, length);
+
+    let StftScratch {
+      workers, divider, ..
+    } = scratch;
+    let divider = divider.view();
+
+    // the rows are split into ranges of samples like the frames of `stft_into`
+    let splits = workers.len().div_ceil(batch_num).max(1);
+    let per_split = length.div_ceil(splits).max(1);
+
+    let mut jobs = Vec::with_capacity(batch_num * splits);
+    for (row, mut rest) in input.outer_iter().zip(output.outer_iter_mut()) {
+      let mut offset = 0;
+      while offset < length {
+        let take = per_split.min(length - offset);
+        let (head, tail) = rest.split_at(Axis(0), take);
+        jobs.push((row, head, offset));
+        rest = tail;
+        offset += take;
+      }
+    }
+
+    run_jobs(jobs, workers, |(row, out, offset), worker| {
+      let divider = divider.slice(s![offset..offset + out.len()]);
+      self.inverse_range(row, out, offset, divider, worker)
+    })
+    .map_err(Error::Dsp)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn signal(batch_num: usize, length: usize) -> Array2<f64> {
+    Array2::from_shape_fn((batch_num, length), |(row, i)| {
+      let t = i as f64 + row as f64 * 13.0;
+      (t * 0.37).sin() + 0.5 * (t * 0.011).cos()
+    })
+  }
+
+  #[test]
+  fn threads_give_identical_results() {
+    let input = signal(2, 5000);
+    let single = StftConfig::new(1024, 256).build().unwrap();
+    let threaded = StftConfig::new(1024, 256).with_threads(7).build().unwrap();
+
+    let spec = single.stft(input.view()).unwrap();
+    assert_eq!(spec, threaded.stft(input.view()).unwrap());
+
+    let wave = single.istft(spec.view(), Some(5000)).unwrap();
+    assert_eq!(wave, threaded.istft(spec.view(), Some(5000)).unwrap());
+  }
+
+  #[test]
+  fn round_trip() {
+    let input = signal(2, 5000);
+    let windows = [
+      Window::Hann,
+      Window::Hamming,
+      Window::Blackman,
+      Window::Kaiser(12.0),
+    ];
+    let pad_modes = [PadMode::Reflect, PadMode::Constant, PadMode::Replicate];
+
+    for window in windows {
+      for center in [true, false] {
+        for pad_mode in pad_modes {
+          let stft = StftConfig::new(1024, 256)
+            .with_window(window)
+            .with_center(center)
+            .with_pad_mode(pad_mode)
+            .build()
+            .unwrap();
+          let spec = stft.stft(input.view()).unwrap();
+          let wave = stft.istft(spec.view(), Some(5000)).unwrap();
+
+          // without centering, the edges are only covered by the tails of
+          // the first and last frames, and the end is not covered at all
+          let range = if center {
+            0..5000
+          } else {
+            1024..stft.default_length(stft.frames(5000)) - 1024
+          };
+          let err = (&wave.slice(s![.., range.clone()]) - &input.slice(s![.., range]))
+            .fold(0.0f64, |res, &x| res.max(x.abs()));
+          assert!(err < 1e-9, "{window:?}, {center}, {pad_mode:?}: {err}");
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn exact_length() {
+    let stft = StftConfig::new(1024, 256).build().unwrap();
+
+    // lengths which are and are not a multiple of the hop length
+    for length in [4096, 5000, 5001] {
+      let input = signal(2, length);
+      let spec = stft.stft(input.view()).unwrap();
+      let wave = stft.istft(spec.view(), Some(length)).unwrap();
+
+      assert_eq!(wave.dim(), input.dim());
+      let err = (&wave - &input).fold(0.0f64, |res, &x| res.max(x.abs()));
+      assert!(err < 1e-9, "{length}: {err}");
+    }
+  }
+}
@@ -0,0 +1,75 @@
+use std::f64::consts::PI;
+
+use ndarray::{s, Array1};
+
+use crate::utils::hann_window;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Window {
+  #[default]
+  Hann,
+  Hamming,
+  Blackman,
+  // the shape parameter `beta`
+  Kaiser(f64),
+}
+
+// Modified Bessel function of the first kind, order 0
+fn bessel_i0(x: f64) -> f64 {
+  let half = x / 2.0;
+  let mut term = 1.0;
+  let mut res = 1.0;
+
+  for k in 1.. {
+    term *= half / k as f64;
+    let cur = term * term;
+    res += cur;
+    if cur < res * f64::EPSILON {
+      break;
+    }
+  }
+
+  res
+}
+
+fn symmetric(window_length: usize, f: impl Fn(f64) -> f64) -> Array1<f64> {
+  match window_length {
+    0 => Array1::zeros(0),
+    1 => Array1::ones(1),
+    _ => {
+      let denom = (window_length - 1) as f64;
+      Array1::from_shape_fn(window_length, |i| f(i as f64 / denom))
+    }
+  }
+}
+
+impl Window {
+  // Same as the window functions of PyTorch, a periodic window is
+  // the symmetric window of `window_length + 1` without the last sample
+  pub fn build(&self, window_length: usize, periodic: bool) -> Array1<f64> {
+    if let Self::Hann = self {
+      return hann_window(window_length, periodic);
+    }
+
+    if periodic && window_length > 1 {
+      let res = self.build(window_length + 1, false);
+      return res.slice(s![..window_length]).to_owned();
+    }
+
+    // `pos` goes from 0 to 1 across the window
+    match *self {
+      Self::Hann => unreachable!(),
+      Self::Hamming => symmetric(window_length, |pos| 0.54 - 0.46 * (2.0 * PI * pos).cos()),
+      Self::Blackman => symmetric(window_length, |pos| {
+        0.42 - 0.5 * (2.0 * PI * pos).cos() + 0.08 * (4.0 * PI * pos).cos()
+      }),
+      Self::Kaiser(beta) => {
+        let scale = bessel_i0(beta);
+        symmetric(window_length, |pos| {
+          let ratio = 2.0 * pos - 1.0;
+          bessel_i0(beta * (1.0 - ratio * ratio).max(0.0).sqrt()) / scale
+        })
+      }
+    }
+  }
+}
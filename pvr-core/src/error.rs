@@ -0,0 +1,52 @@
+use std::{io, path::PathBuf};
+
+use thiserror::Error;
+
+use crate::cancel::Cancelled;
+
+// The causes are kept as `anyhow::Error` with their context,
+// the variant tells what kind of failure it is
+#[derive(Debug, Error)]
+pub enum Error {
+  #[error("Model not found at {}", .0.display())]
+  ModelNotFound(PathBuf),
+  // the model can not be parsed or does not fit the separator
+  #[error("Invalid model: {0:#}")]
+  InvalidModel(anyhow::Error),
+  // ONNX Runtime or the execution providers failed to load
+  #[error("Failed to initialize the backend: {0:#}")]
+  BackendInit(anyhow::Error),
+  #[error("Failed to inference: {0:#}")]
+  Inference(anyhow::Error),
+  // STFT, resampling and the other signal processing
+  #[error("DSP error: {0:#}")]
+  Dsp(anyhow::Error),
+  // invalid parameters given by the caller
+  #[error("Invalid config: {0:#}")]
+  Config(anyhow::Error),
+  #[error("Invalid audio: {0:#}")]
+  Audio(anyhow::Error),
+  #[error(transparent)]
+  Io(#[from] io::Error),
+  #[error("Cancelled")]
+  Cancelled,
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl From<Cancelled> for Error {
+  fn from(_: Cancelled) -> Self {
+    Self::Cancelled
+  }
+}
+
+// Same as `anyhow::ensure!`, failing with the given variant
+macro_rules! ensure_as {
+  ($variant:ident, $cond:expr, $($arg:tt)+) => {
+    if !$cond {
+      return Err($crate::Error::$variant(anyhow::anyhow!($($arg)+)));
+    }
+  };
+}
+
+pub(crate) use ensure_as;
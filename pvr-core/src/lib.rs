@@ -1,5 +1,14 @@
+pub mod audio;
+pub mod cancel;
+#[cfg(feature = "mdx")]
 pub mod config;
+pub mod dsp;
+mod error;
 mod model;
+pub mod progress;
+#[cfg(feature = "async")]
+pub mod task;
 pub mod utils;
 
+pub use error::{Error, Result};
 pub use model::*;
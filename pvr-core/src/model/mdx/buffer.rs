@@ -0,0 +1,270 @@
+use anyhow::{bail, ensure};
+use half::f16;
+use ndarray::{prelude::*, Zip};
+use ort::{IoBinding, Value};
+
+use super::{
+  io::Precision,
+  stft::{Stft, StftScratch},
+  HighEnd,
+};
+use crate::{Error, Result};
+
+// The input and output of the model, made by the engine
+pub enum Tensors<'s> {
+  // bound to the session once, ONNX Runtime reads the input and writes
+  // the prediction in place on every run of the binding
+  Ort {
+    binding: IoBinding<'s>,
+    input: Value,
+    input_precision: Precision,
+    output: Value,
+    output_precision: Precision,
+  },
+  // tract, and the stub engine of the tests, take a copy of the input
+  // and return a new output
+  #[cfg(any(feature = "tract", test))]
+  Host(Array4<f32>),
+}
+
+// Buffers reused by every chunk, the tensors are only run by the engine
+// which made them, so they are not allocated or bound again per chunk
+pub struct ChunkBuffers<'s> {
+  tensors: Tensors<'s>,
+  // index of the engine which made the tensors
+  engine: usize,
+  dim_f: usize,
+  high_end: HighEnd,
+  compensate: f64,
+  // holds the STFT result first, then the prediction of the model,
+  // the bins above `dim_f` are kept for `high_end`
+  spec: Array4<f64>,
+  wave: Array3<f64>,
+  scratch: StftScratch,
+}
+
+impl<'s> ChunkBuffers<'s> {
+  pub fn new(
+    stft: &Stft,
+    tensors: Tensors<'s>,
+    engine: usize,
+    high_end: HighEnd,
+    compensate: f64,
+    input_shape: (usize, usize, usize, usize),
+    wave_shape: (usize, usize, usize),
+  ) -> Self {
+    let (b, c, dim_f, t) = input_shape;
+    let spec_bins = high_end.spec_bins(stft.n_fft(), dim_f);
+
+    Self {
+      tensors,
+      engine,
+      dim_f,
+      high_end,
+      compensate,
+      spec: Array4::zeros((b, c, spec_bins, t)),
+      wave: Array3::zeros(wave_shape),
+      scratch: stft.make_scratch(),
+    }
+  }
+
+  pub fn load_input(&mut self, stft: &Stft, mix: ArrayView3<f64>) -> Result<()> {
+    stft.apply(mix, self.spec.view_mut(), &mut self.scratch)?;
+    let spek = self.spec.slice(s![.., .., ..self.dim_f, ..]);
+
+    match &mut self.tensors {
+      Tensors::Ort {
+        input,
+        input_precision: Precision::F32,
+        ..
+      } => fill_input(input_view(input.extract_tensor_mut::<f32>())?, spek, |x| {
+        x as f32
+      }),
+      Tensors::Ort {
+        input,
+        input_precision: Precision::F16,
+        ..
+      } => fill_input(
+        input_view(input.extract_tensor_mut::<f16>())?,
+        spek,
+        f16::from_f64,
+      ),
+      #[cfg(any(feature = "tract", test))]
+      Tensors::Host(input) => fill_input(input.view_mut(), spek, |x| x as f32),
+    }
+
+    Ok(())
+  }
+
+  pub fn engine(&self) -> usize {
+    self.engine
+  }
+
+  pub fn tensors_mut(&mut self) -> &mut Tensors<'s> {
+    &mut self.tensors
+  }
+
+  // Called by the engines taking the output by value
+  pub fn store_output<T: Copy + Into<f64>>(
+    &mut self,
+    spec_pred: ArrayViewD<T>,
+  ) -> anyhow::Result<()> {
+    store_spec(
+      &mut self.spec,
+      self.dim_f,
+      self.high_end,
+      self.compensate,
+      spec_pred,
+    )
+  }
+
+  // Called by ONNX Runtime once it has written the bound output
+  pub fn store_bound_output(&mut self) -> anyhow::Result<()> {
+    let (output, precision) = match &self.tensors {
+      Tensors::Ort {
+        output,
+        output_precision,
+        ..
+      } => (output, output_precision),
+      #[cfg(any(feature = "tract", test))]
+      _ => bail!("The output is not bound to ONNX Runtime"),
+    };
+
+    match precision {
+      Precision::F32 => store_spec(
+        &mut self.spec,
+        self.dim_f,
+        self.high_end,
+        self.compensate,
+        output.extract_tensor::<f32>()?.view(),
+      ),
+      Precision::F16 => store_spec(
+        &mut self.spec,
+        self.dim_f,
+        self.high_end,
+        self.compensate,
+        output.extract_tensor::<f16>()?.view(),
+      ),
+    }
+  }
+
+  pub fn inverse(&mut self, stft: &Stft, length: usize) -> Result<ArrayViewMut3<f64>> {
+    let mut wave = self.wave.slice_mut(s![.., .., ..length]);
+    stft.inverse(self.spec.view(), wave.view_mut(), &mut self.scratch)?;
+    Ok(wave)
+  }
+
+  // The first `length` samples of the last `inverse`
+  pub fn wave(&self, length: usize) -> ArrayView3<f64> {
+    self.wave.slice(s![.., .., ..length])
+  }
+}
+
+fn input_view<T>(input: Result<ArrayViewMutD<T>, ort::Error>) -> Result<ArrayViewMut4<T>> {
+  input
+    .map_err(anyhow::Error::from)
+    .and_then(|input| Ok(input.into_dimensionality::<Ix4>()?))
+    .map_err(Error::Inference)
+}
+
+// The lowest 3 frequency bins are zeroed, as UVR does
+fn fill_input<T: Copy>(
+  mut input: ArrayViewMut4<T>,
+  spek: ArrayView4<f64>,
+  convert: impl Fn(f64) -> T,
+) {
+  Zip::from(&mut input)
+    .and(&spek)
+    .for_each(|a, &b| *a = convert(b));
+  input.slice_mut(s![.., .., ..3, ..]).fill(convert(0.0));
+}
+
+// A mismatched shape is an inference error, the dimensions of models
+// with dynamic axes are only known once they have run. The prediction is
+// compensated before `high_end` fills the bins above `dim_f`, so the band
+// copied from the mixture is left as it is
+fn store_spec<T: Copy + Into<f64>>(
+  spec: &mut Array4<f64>,
+  dim_f: usize,
+  high_end: HighEnd,
+  compensate: f64,
+  spec_pred: ArrayViewD<T>,
+) -> anyhow::Result<()> {
+  let (b, c, _, t) = spec.dim();
+  ensure!(
+    spec_pred.shape() == [b, c, dim_f, t],
+    "The model returned a spectrogram of {:?}, expected {:?}",
+    spec_pred.shape(),
+    [b, c, dim_f, t]
+  );
+  let spec_pred = spec_pred.into_dimensionality::<Ix4>()?;
+
+  Zip::from(&mut spec.slice_mut(s![.., .., ..dim_f, ..]))
+    .and(&spec_pred)
+    .for_each(|a, &b| *a = b.into() * compensate);
+
+  high_end.apply(spec.view_mut(), dim_f);
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn f16_matches_f32() {
+    let shape = (1, 4, 16, 8);
+    let spek = Array4::from_shape_fn(shape, |(_, c, f, t)| {
+      ((c * 131 + f * 17 + t) as f64 * 0.7).sin() * 40.0
+    });
+
+    let mut single = Array4::<f32>::zeros(shape);
+    fill_input(single.view_mut(), spek.view(), |x| x as f32);
+    let mut half = Array4::from_elem(shape, f16::ZERO);
+    fill_input(half.view_mut(), spek.view(), f16::from_f64);
+
+    let mut expected = Array4::zeros(shape);
+    store_spec(
+      &mut expected,
+      16,
+      HighEnd::Cut,
+      1.0,
+      single.view().into_dyn(),
+    )
+    .unwrap();
+    let mut res = Array4::zeros(shape);
+    store_spec(&mut res, 16, HighEnd::Cut, 1.0, half.view().into_dyn()).unwrap();
+
+    // float16 keeps 11 bits of the mantissa
+    Zip::from(&res).and(&expected).for_each(|&a, &b| {
+      assert!((a - b).abs() <= b.abs() * 1e-3 + 1e-4, "{a} != {b}");
+    });
+  }
+
+  #[test]
+  fn compensation_leaves_the_mixture_band() {
+    let mix = Array4::from_shape_fn((1, 4, 24, 8), |(_, c, f, t)| {
+      ((c * 131 + f * 17 + t) as f64 * 0.7).sin() * 40.0
+    });
+    let pred = mix.slice(s![.., .., ..16, ..]).mapv(|x| x as f32 * 0.5);
+
+    let mut spec = mix.clone();
+    store_spec(
+      &mut spec,
+      16,
+      HighEnd::Mixture,
+      1.035,
+      pred.view().into_dyn(),
+    )
+    .unwrap();
+
+    assert_eq!(
+      spec.slice(s![.., .., 16.., ..]),
+      mix.slice(s![.., .., 16.., ..])
+    );
+    Zip::from(spec.slice(s![.., .., ..16, ..]))
+      .and(&pred)
+      .for_each(|&a, &b| assert_eq!(a, b as f64 * 1.035));
+  }
+}
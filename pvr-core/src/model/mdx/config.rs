@@ -1,10 +1,15 @@
-use std::{env, fmt, path::PathBuf};
+use std::{borrow::Cow, env, fmt, path::PathBuf};
 
-use anyhow::{Context, Result};
-use ort::{GraphOptimizationLevel, Session};
+use anyhow::{anyhow, Context};
 
-use super::{MdxSeperator, Stft};
+use super::{io, metadata::read_model, proto, Engine, HighEnd, MdxMetadata, MdxSeperator, Stft};
+use crate::{
+  config::{Backend, SessionOptions},
+  error::{ensure_as, Error, Result},
+  utils::hann_window,
+};
 
+#[derive(Clone, Debug)]
 pub enum MdxType {
   Vocals,
   Instrumental,
@@ -39,16 +44,36 @@ impl MdxType {
   }
 }
 
+#[derive(Clone, Debug)]
+enum ModelFile {
+  Preset(&'static str),
+  Path(PathBuf),
+}
+
+#[derive(Clone, Debug)]
 pub struct MdxConfig {
-  pub name: &'static str,
-  filename: &'static str,
-  pub model_type: MdxType,
-  n_fft: usize,
-  dim_t: u8,
-  dim_f: usize,
-  compensate: f64,
+  pub name: Cow<'static, str>,
+  file: ModelFile,
+  pub model_type: Option<MdxType>,
+  n_fft: Option<usize>,
+  dim_t: Option<u8>,
+  dim_f: Option<usize>,
+  compensate: Option<f64>,
+  hop_length: Option<usize>,
+  sample_rate: Option<u32>,
+  stft_threads: usize,
+  workers: usize,
+  sessions: usize,
+  pipeline: bool,
+  high_end: HighEnd,
+  session: SessionOptions,
 }
 
+const DEFAULT_HOP_LENGTH: usize = 1024;
+const DEFAULT_SAMPLE_RATE: u32 = 44100;
+// UVR models use up to 2 ^ 9 frames per chunk
+const MAX_DIM_T: u8 = 16;
+
 impl MdxConfig {
   pub const fn new(
     name: &'static str,
@@ -60,17 +85,134 @@ impl MdxConfig {
     compensate: f64,
   ) -> Self {
     Self {
-      name,
-      filename,
-      model_type,
-      n_fft,
-      dim_t,
-      dim_f,
-      compensate,
+      name: Cow::Borrowed(name),
+      file: ModelFile::Preset(filename),
+      model_type: Some(model_type),
+      n_fft: Some(n_fft),
+      dim_t: Some(dim_t),
+      dim_f: Some(dim_f),
+      compensate: Some(compensate),
+      hop_length: None,
+      sample_rate: None,
+      stft_threads: 1,
+      workers: 1,
+      sessions: 1,
+      pipeline: false,
+      high_end: HighEnd::Cut,
+      session: SessionOptions::new(),
+    }
+  }
+
+  // All the parameters will be read from the metadata of the model
+  pub fn from_file(path: impl Into<PathBuf>) -> Self {
+    let path = path.into();
+    let name = path
+      .file_stem()
+      .map(|s| s.to_string_lossy().into_owned())
+      .unwrap_or_default();
+
+    Self {
+      name: Cow::Owned(name),
+      file: ModelFile::Path(path),
+      model_type: None,
+      n_fft: None,
+      dim_t: None,
+      dim_f: None,
+      compensate: None,
+      hop_length: None,
+      sample_rate: None,
+      stft_threads: 1,
+      workers: 1,
+      sessions: 1,
+      pipeline: false,
+      high_end: HighEnd::Cut,
+      session: SessionOptions::new(),
+    }
+  }
+
+  pub const fn with_hop_length(mut self, hop_length: usize) -> Self {
+    self.hop_length = Some(hop_length);
+    self
+  }
+
+  pub const fn with_sample_rate(mut self, sample_rate: u32) -> Self {
+    self.sample_rate = Some(sample_rate);
+    self
+  }
+
+  pub const fn with_high_end(mut self, high_end: HighEnd) -> Self {
+    self.high_end = high_end;
+    self
+  }
+
+  pub fn with_backends(mut self, backends: impl Into<Vec<Backend>>) -> Self {
+    self.session = self.session.with_backends(backends);
+    self
+  }
+
+  pub fn with_session_options(mut self, session: SessionOptions) -> Self {
+    self.session = session;
+    self
+  }
+
+  // Threads used by STFT and iSTFT of each chunk
+  pub const fn with_stft_threads(mut self, stft_threads: usize) -> Self {
+    self.stft_threads = stft_threads;
+    self
+  }
+
+  // Chunks separated at the same time, each worker has its own buffers
+  pub const fn with_workers(mut self, workers: usize) -> Self {
+    self.workers = workers;
+    self
+  }
+
+  // Sessions of the model shared by the workers in turn, more sessions use
+  // more memory but do not wait for each other inside ONNX Runtime
+  pub const fn with_sessions(mut self, sessions: usize) -> Self {
+    self.sessions = sessions;
+    self
+  }
+
+  // Runs the STFT, the inference and the iSTFT of consecutive chunks at the same
+  // time on their own threads, so the model does not wait for the signal processing
+  pub const fn with_pipeline(mut self, pipeline: bool) -> Self {
+    self.pipeline = pipeline;
+    self
+  }
+
+  // Tells apart the separators built from the configs, the same model file
+  // loaded with other options is another separator
+  pub fn cache_key(&self) -> String {
+    format!("{:?} {self:?}", self.model_path())
+  }
+
+  // The parameters given by this config, used to annotate the model
+  pub fn metadata(&self) -> MdxMetadata {
+    MdxMetadata {
+      n_fft: self.n_fft,
+      dim_f: self.dim_f,
+      dim_t: self.dim_t,
+      compensate: self.compensate,
+      hop_length: self.hop_length,
+      sample_rate: self.sample_rate,
+      primary_stem: self
+        .model_type
+        .as_ref()
+        .map(|t| t.get_primary_stem().to_owned()),
+      secondary_stem: self
+        .model_type
+        .as_ref()
+        .map(|t| t.get_secondary_stem().to_owned()),
     }
   }
 
   fn model_path(&self) -> PathBuf {
+    let filename = match &self.file {
+      ModelFile::Preset(filename) => filename,
+      ModelFile::Path(path) => return path.clone(),
+    };
+
     env::var("PVR_MODELS")
       .map(|s| PathBuf::from(s))
       .unwrap_or_else(|_| {
@@ -80,7 +222,7 @@ impl MdxConfig {
           .expect("Failed to get the parent path of exe")
           .join("models")
       })
-      .join(self.filename)
+      .join(filename)
   }
 
   pub fn exists(&self) -> bool {
@@ -89,26 +231,169 @@ impl MdxConfig {
 
   pub fn build(&self) -> Result<MdxSeperator> {
     tracing::info!(
-      name = self.name,
-      r#type = %self.model_type,
+      name = %self.name,
+      r#type = self.model_type.as_ref().map(tracing::field::display),
       "Building model..."
     );
 
-    let model = Session::builder()
-      .context("Failed to get ort session builder")?
-      .with_optimization_level(GraphOptimizationLevel::Level3)
-      .context("Failed to optimize ort session")?
-      .commit_from_file(self.model_path())
-      .context("Failed to load onnx model")?;
+    let model = read_model(&self.model_path())?;
+
+    // the parameters given by config take precedence over the metadata
+    let meta = MdxMetadata::read(&model)?;
+
+    let n_fft = self.n_fft.or(meta.n_fft).ok_or_else(|| {
+      Error::InvalidModel(anyhow!(
+        "`n_fft` is neither given nor found in the model metadata"
+      ))
+    })?;
+    let compensate = self.compensate.or(meta.compensate).unwrap_or(1.0);
+
+    let hop_length = self
+      .hop_length
+      .or(meta.hop_length)
+      .unwrap_or(DEFAULT_HOP_LENGTH);
+    let sample_rate = self
+      .sample_rate
+      .or(meta.sample_rate)
+      .unwrap_or(DEFAULT_SAMPLE_RATE);
+
+    ensure_as!(
+      Config,
+      hop_length > 0 && hop_length <= n_fft,
+      "Invalid hop length {hop_length} for `n_fft` {n_fft}"
+    );
+    ensure_as!(Config, sample_rate > 0, "Invalid sample rate {sample_rate}");
+    ensure_as!(Config, self.workers > 0, "At least one worker is needed");
+    ensure_as!(Config, self.sessions > 0, "At least one session is needed");
+    ensure_as!(
+      Config,
+      !self.pipeline || self.workers == 1,
+      "The pipeline runs the model on one chunk at a time, it can not be used with {} workers",
+      self.workers
+    );
+
+    let primary_stem = self
+      .model_type
+      .as_ref()
+      .map(|t| t.get_primary_stem().to_owned())
+      .or(meta.primary_stem)
+      .unwrap_or_else(|| "primary".to_owned());
+    let secondary_stem = self
+      .model_type
+      .as_ref()
+      .map(|t| t.get_secondary_stem().to_owned())
+      .or(meta.secondary_stem)
+      .unwrap_or_else(|| "secondary".to_owned());
+
+    // a chunk has `2 ^ dim_t` frames, checked before anything is shifted by it
+    if let Some(dim_t) = self.dim_t {
+      ensure_as!(
+        Config,
+        dim_t <= MAX_DIM_T,
+        "`dim_t` {dim_t} is too large, at most {MAX_DIM_T} is supported"
+      );
+    }
+    if let Some(dim_t) = meta.dim_t {
+      ensure_as!(
+        InvalidModel,
+        dim_t <= MAX_DIM_T,
+        "`dim_t` {dim_t} in the metadata is too large, at most {MAX_DIM_T} is supported"
+      );
+    }
+
+    let (inputs, outputs) = proto::graph_io(&model).map_err(Error::InvalidModel)?;
+    let io = io::inspect(
+      &inputs,
+      &outputs,
+      self.dim_f.or(meta.dim_f),
+      self.dim_t.or(meta.dim_t),
+    )
+    .context("Mismatched model")
+    .map_err(Error::InvalidModel)?;
+
+    // the one derived from the frames of the graph
+    ensure_as!(
+      InvalidModel,
+      io.dim_t <= MAX_DIM_T,
+      "The model has 2 ^ {} frames, at most 2 ^ {MAX_DIM_T} are supported",
+      io.dim_t
+    );
+
+    let segment_size = 1 << io.dim_t; // TODO: support other segment size
+    ensure_as!(
+      InvalidModel,
+      io.dim_f <= n_fft / 2 + 1,
+      "`dim_f` {} exceeds the number of frequency bins",
+      io.dim_f
+    );
+    ensure_as!(
+      Config,
+      hop_length * (segment_size - 1) > n_fft,
+      "The chunk is too short for `n_fft` {n_fft}"
+    );
+
+    // a session more than the workers would never run
+    let sessions = self.sessions.min(self.workers);
+    let (engine, backend) = Engine::new(&self.session, &model, &io)?;
+    let mut engines = vec![engine];
+    for _ in 1..sessions {
+      let (engine, _) = Engine::new(&self.session, &model, &io)?;
+      engines.push(engine);
+    }
 
-    let stft = Stft::new(self.n_fft, 1024, self.dim_f);
+    tracing::info!(
+      input = io.input_name,
+      output = io.output_name,
+      precision = ?io.input_precision,
+      n_fft,
+      hop_length,
+      sample_rate,
+      dim_f = io.dim_f,
+      dim_t = io.dim_t,
+      workers = self.workers,
+      sessions,
+      pipeline = self.pipeline,
+      "Model loaded"
+    );
+
+    let stft = Stft::new(n_fft, hop_length, self.stft_threads)?;
 
     Ok(MdxSeperator {
-      n_fft: self.n_fft,
-      segment_size: 1 << self.dim_t, // TODO: support other segment size
+      n_fft,
+      hop_length,
+      sample_rate,
+      segment_size,
+      dim_f: io.dim_f,
+      high_end: self.high_end,
+      backend,
       stft,
-      model,
-      compensate: self.compensate,
+      engines,
+      workers: self.workers,
+      pipeline: self.pipeline,
+      window: hann_window(hop_length * (segment_size - 1), false),
+      compensate,
+      primary_stem,
+      secondary_stem,
     })
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn cache_keys_tell_the_options_apart() {
+    let config = MdxConfig::from_file("a/model.onnx");
+
+    assert_eq!(config.cache_key(), config.clone().cache_key());
+    assert_ne!(
+      config.cache_key(),
+      MdxConfig::from_file("b/model.onnx").cache_key()
+    );
+    assert_ne!(
+      config.cache_key(),
+      config.clone().with_workers(2).cache_key()
+    );
+  }
+}
@@ -0,0 +1,199 @@
+use anyhow::Result;
+use ndarray::Array4;
+use ort::{Session, Value};
+
+#[cfg(not(feature = "tract"))]
+use anyhow::anyhow;
+#[cfg(any(feature = "tract", test))]
+use anyhow::bail;
+#[cfg(feature = "tract")]
+use anyhow::Context;
+
+use super::{
+  buffer::{ChunkBuffers, Tensors},
+  io::{ModelIo, Precision},
+};
+#[cfg(feature = "tract")]
+use crate::error::ensure_as;
+use crate::{
+  config::{Backend, SessionOptions},
+  error::Error,
+};
+
+#[cfg(feature = "tract")]
+type TractModel = tract_onnx::prelude::TypedRunnableModel<tract_onnx::prelude::TypedModel>;
+
+// Runs the model on the input buffer and stores the prediction back
+pub enum Engine {
+  Ort {
+    session: Session,
+    input_name: String,
+    input_precision: Precision,
+    output_name: String,
+    output_precision: Precision,
+  },
+  #[cfg(feature = "tract")]
+  Tract {
+    model: TractModel,
+    output_index: usize,
+  },
+  // halves the spectrogram, so the ways of running the chunks can be compared
+  #[cfg(test)]
+  Stub,
+}
+
+impl Engine {
+  // Returns the engine and its first registered backend, `model` is the content of the ONNX model.
+  // A graph rejected by the engine is an `InvalidModel` error, the rest are `BackendInit`
+  pub fn new(
+    options: &SessionOptions,
+    model: &[u8],
+    io: &ModelIo,
+  ) -> crate::Result<(Self, Backend)> {
+    if options.backends().first() == Some(&Backend::Tract) {
+      return Ok((Self::tract(model, io)?, Backend::Tract));
+    }
+
+    let (session, backend) = options.commit(model)?;
+
+    let engine = Self::Ort {
+      session,
+      input_name: io.input_name.clone(),
+      input_precision: io.input_precision,
+      output_name: io.output_name.clone(),
+      output_precision: io.output_precision,
+    };
+
+    Ok((engine, backend))
+  }
+
+  // The shape of the input is fixed, so tract can optimize the whole graph
+  #[cfg(feature = "tract")]
+  fn tract(model: &[u8], io: &ModelIo) -> crate::Result<Self> {
+    use tract_onnx::prelude::*;
+
+    ensure_as!(
+      InvalidModel,
+      matches!(
+        (io.input_precision, io.output_precision),
+        (Precision::F32, Precision::F32)
+      ),
+      "The tract backend only supports float32 models"
+    );
+
+    let shape = [1, 4, io.dim_f, 1 << io.dim_t];
+    let model = tract_onnx::onnx()
+      .model_for_read(&mut &model[..])
+      .context("Failed to load onnx model")
+      .and_then(|model| model.with_input_fact(0, f32::fact(shape).into()))
+      .and_then(|model| {
+        model
+          .into_optimized()
+          .context("Failed to optimize onnx model")
+      })
+      .and_then(|model| model.into_runnable())
+      .map_err(Error::InvalidModel)?;
+
+    tracing::info!(backend = %Backend::Tract, "Model compiled");
+
+    Ok(Self::Tract {
+      model,
+      output_index: io.output_index,
+    })
+  }
+
+  #[cfg(not(feature = "tract"))]
+  fn tract(_model: &[u8], _io: &ModelIo) -> crate::Result<Self> {
+    Err(Error::BackendInit(anyhow!(
+      "This build does not include the tract backend"
+    )))
+  }
+
+  // The tensors of a buffer, allocated once, `shape` is the one of both the input and the output
+  pub fn tensors(&self, shape: (usize, usize, usize, usize)) -> Result<Tensors<'_>> {
+    match self {
+      Self::Ort {
+        session,
+        input_name,
+        input_precision,
+        output_name,
+        output_precision,
+      } => {
+        let input = zeros(*input_precision, shape)?;
+        let mut output = zeros(*output_precision, shape)?;
+
+        // ONNX Runtime keeps its own references to the tensors, which are
+        // still written and read through these values between the runs
+        let mut binding = session.create_binding()?;
+        binding.bind_input(input_name, &input)?;
+        binding.bind_output(output_name, &mut output)?;
+
+        Ok(Tensors::Ort {
+          binding,
+          input,
+          input_precision: *input_precision,
+          output,
+          output_precision: *output_precision,
+        })
+      }
+      #[cfg(feature = "tract")]
+      Self::Tract { .. } => Ok(Tensors::Host(Array4::zeros(shape))),
+      #[cfg(test)]
+      Self::Stub => Ok(Tensors::Host(Array4::zeros(shape))),
+    }
+  }
+
+  pub fn run(&self, buffers: &mut ChunkBuffers) -> Result<()> {
+    match self {
+      Self::Ort { .. } => {
+        let binding = match buffers.tensors_mut() {
+          Tensors::Ort { binding, .. } => binding,
+          #[cfg(any(feature = "tract", test))]
+          _ => bail!("The buffers are not made for ONNX Runtime"),
+        };
+
+        // the outputs returned only list the bound tensor, which is read in place
+        binding.run()?;
+
+        buffers.store_bound_output()
+      }
+      #[cfg(feature = "tract")]
+      Self::Tract {
+        model,
+        output_index,
+      } => {
+        use tract_onnx::prelude::{tvec, Tensor as TractTensor};
+
+        let Tensors::Host(input) = buffers.tensors_mut() else {
+          bail!("The buffers are not made for tract");
+        };
+        let input = TractTensor::from_shape(
+          input.shape(),
+          input
+            .as_slice()
+            .context("The input buffer is not contiguous")?,
+        )?;
+
+        let outputs = model.run(tvec!(input.into()))?;
+        buffers.store_output(outputs[*output_index].to_array_view::<f32>()?)
+      }
+      #[cfg(test)]
+      Self::Stub => {
+        let Tensors::Host(input) = buffers.tensors_mut() else {
+          bail!("The buffers are not made for the stub engine");
+        };
+        let output = input.mapv(|x| x * 0.5);
+        buffers.store_output(output.view().into_dyn())
+      }
+    }
+  }
+}
+
+fn zeros(precision: Precision, shape: (usize, usize, usize, usize)) -> Result<Value> {
+  let value = match precision {
+    Precision::F32 => Value::from_array(Array4::<f32>::zeros(shape))?,
+    Precision::F16 => Value::from_array(Array4::from_elem(shape, half::f16::ZERO))?,
+  };
+
+  Ok(value)
+}
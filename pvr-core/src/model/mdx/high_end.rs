@@ -0,0 +1,156 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::anyhow;
+use ndarray::prelude::*;
+
+use crate::Error;
+
+// How to fill the frequency bins above `dim_f` of the primary stem
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HighEnd {
+  // zeros, all the content ends up in the secondary stem
+  #[default]
+  Cut,
+  // copy the band from the mixture, all the content ends up in the primary stem
+  Mixture,
+  // mirror the magnitude of the prediction below the cut-off with the phase
+  // of the mixture, and never louder than the mixture, same as UVR
+  Mirror,
+}
+
+impl fmt::Display for HighEnd {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      HighEnd::Cut => write!(f, "cut"),
+      HighEnd::Mixture => write!(f, "mixture"),
+      HighEnd::Mirror => write!(f, "mirror"),
+    }
+  }
+}
+
+impl FromStr for HighEnd {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "cut" => Ok(Self::Cut),
+      "mixture" => Ok(Self::Mixture),
+      "mirror" => Ok(Self::Mirror),
+      _ => Err(Error::Config(anyhow!("Unknown high end mode `{s}`"))),
+    }
+  }
+}
+
+// UVR mirrors around 10 bins below the cut-off
+const MIRROR_OFFSET: usize = 10;
+
+impl HighEnd {
+  // Bins of the STFT needed by this mode
+  pub fn spec_bins(&self, n_fft: usize, dim_f: usize) -> usize {
+    match self {
+      HighEnd::Cut => dim_f,
+      HighEnd::Mixture | HighEnd::Mirror => n_fft / 2 + 1,
+    }
+  }
+
+  // `spec` is [b, c * (re, im), bins, frames], the bins below `dim_f` hold the
+  // prediction and the rest hold the mixture
+  pub fn apply(&self, mut spec: ArrayViewMut4<f64>, dim_f: usize) {
+    if *self != HighEnd::Mirror {
+      return;
+    }
+
+    let (_, c, bins, _) = spec.dim();
+    let pivot = dim_f.saturating_sub(MIRROR_OFFSET + 1);
+
+    for mut spec in spec.outer_iter_mut() {
+      for ch in (0..c).step_by(2) {
+        for k in dim_f..bins {
+          let src = pivot.saturating_sub(k - dim_f);
+
+          for t in 0..spec.len_of(Axis(2)) {
+            let (re, im) = (spec[[ch, k, t]], spec[[ch + 1, k, t]]);
+            let (src_re, src_im) = (spec[[ch, src, t]], spec[[ch + 1, src, t]]);
+
+            let mix_mag = re.hypot(im);
+            let mirror_mag = src_re.hypot(src_im);
+
+            if mix_mag > mirror_mag {
+              let scale = mirror_mag / mix_mag;
+              spec[[ch, k, t]] = re * scale;
+              spec[[ch + 1, k, t]] = im * scale;
+            }
+          }
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const N_FFT: usize = 64;
+  const DIM_F: usize = 24;
+
+  // The mixture and the spectrogram filled by `high_end`, the prediction
+  // below `DIM_F` is half the mixture
+  fn fill(high_end: HighEnd) -> (Array4<f64>, Array4<f64>) {
+    let bins = high_end.spec_bins(N_FFT, DIM_F);
+    let mix = Array4::from_shape_fn((1, 4, bins, 8), |(_, c, f, t)| {
+      ((c * 131 + f * 17 + t) as f64 * 0.7).sin() * 40.0
+    });
+
+    let mut spec = mix.clone();
+    spec
+      .slice_mut(s![.., .., ..DIM_F, ..])
+      .mapv_inplace(|x| x * 0.5);
+    high_end.apply(spec.view_mut(), DIM_F);
+
+    (mix, spec)
+  }
+
+  #[test]
+  fn cut_keeps_no_bin_above_dim_f() {
+    let (mix, spec) = fill(HighEnd::Cut);
+
+    assert_eq!(spec.len_of(Axis(2)), DIM_F);
+    assert_eq!(spec, mix.mapv(|x| x * 0.5));
+  }
+
+  #[test]
+  fn mixture_copies_the_bins_above_dim_f() {
+    let (mix, spec) = fill(HighEnd::Mixture);
+
+    assert_eq!(spec.len_of(Axis(2)), N_FFT / 2 + 1);
+    assert_eq!(
+      spec.slice(s![.., .., DIM_F.., ..]),
+      mix.slice(s![.., .., DIM_F.., ..])
+    );
+  }
+
+  #[test]
+  fn mirror_bounds_the_bins_above_dim_f() {
+    let (mix, spec) = fill(HighEnd::Mirror);
+    let pivot = DIM_F - MIRROR_OFFSET - 1;
+
+    assert_eq!(spec.len_of(Axis(2)), N_FFT / 2 + 1);
+    for ch in (0..4).step_by(2) {
+      for k in DIM_F..=N_FFT / 2 {
+        for t in 0..8 {
+          let bin = |spec: &Array4<f64>, k: usize| (spec[[0, ch, k, t]], spec[[0, ch + 1, k, t]]);
+          let ((re, im), (mix_re, mix_im)) = (bin(&spec, k), bin(&mix, k));
+          let (src_re, src_im) = bin(&spec, pivot.saturating_sub(k - DIM_F));
+
+          // as loud as the quieter of the mixture and the mirrored prediction
+          let expected = mix_re.hypot(mix_im).min(src_re.hypot(src_im));
+          assert!((re.hypot(im) - expected).abs() < 1e-9, "bin {k}");
+          // with the phase of the mixture
+          assert!((re * mix_im - im * mix_re).abs() < 1e-6, "bin {k}");
+          assert!(re * mix_re + im * mix_im >= 0.0, "bin {k}");
+        }
+      }
+    }
+  }
+}
@@ -0,0 +1,122 @@
+use anyhow::{anyhow, bail, ensure, Result};
+
+// [batch, channels * (re, im), dim_f, 2 ^ dim_t]
+const SPEC_RANK: usize = 4;
+const SPEC_CHANNELS: i64 = 4;
+
+// `TensorProto.DataType` of ONNX
+const ONNX_FLOAT: i32 = 1;
+const ONNX_FLOAT16: i32 = 10;
+
+// A graph input or output, negative dimensions are not static
+pub struct TensorInfo {
+  pub name: String,
+  pub elem_type: i32,
+  pub dims: Vec<i64>,
+}
+
+// Element type of the spectrogram tensors. Quantized exports which only
+// quantize the weights keep float I/O and run as `F32`, integer I/O is
+// rejected as an invalid model
+#[derive(Clone, Copy, Debug)]
+pub enum Precision {
+  F32,
+  F16,
+}
+
+impl Precision {
+  fn from_tensor(tensor: &TensorInfo) -> Result<Self> {
+    match tensor.elem_type {
+      ONNX_FLOAT => Ok(Self::F32),
+      ONNX_FLOAT16 => Ok(Self::F16),
+      ty => bail!("Unsupported element type {ty} of `{}`", tensor.name),
+    }
+  }
+}
+
+pub struct ModelIo {
+  pub input_name: String,
+  pub input_precision: Precision,
+  pub output_name: String,
+  // position of the output in the graph
+  pub output_index: usize,
+  pub output_precision: Precision,
+  pub dim_f: usize,
+  pub dim_t: u8,
+}
+
+fn check_dims(name: &str, dims: &[i64], dim_f: Option<usize>, dim_t: Option<u8>) -> Result<()> {
+  ensure!(
+    dims.len() == SPEC_RANK,
+    "`{name}` should be {SPEC_RANK}-D, got {dims:?}"
+  );
+  ensure!(
+    dims[1] < 0 || dims[1] == SPEC_CHANNELS,
+    "`{name}` should have {SPEC_CHANNELS} channels, got {}",
+    dims[1]
+  );
+  if let Some(dim_f) = dim_f {
+    ensure!(
+      dims[2] < 0 || dims[2] as usize == dim_f,
+      "`{name}` has {} frequency bins, but `dim_f` is {dim_f}",
+      dims[2]
+    );
+  }
+  if let Some(dim_t) = dim_t {
+    ensure!(
+      dims[3] < 0 || dims[3] as usize == 1 << dim_t,
+      "`{name}` has {} frames, but `dim_t` is {dim_t}",
+      dims[3]
+    );
+  }
+  Ok(())
+}
+
+fn static_dim(dims: &[i64], axis: usize) -> Option<usize> {
+  (dims[axis] > 0).then_some(dims[axis] as usize)
+}
+
+pub fn inspect(
+  inputs: &[TensorInfo],
+  outputs: &[TensorInfo],
+  dim_f: Option<usize>,
+  dim_t: Option<u8>,
+) -> Result<ModelIo> {
+  ensure!(inputs.len() == 1, "The model should have exactly 1 input");
+  ensure!(!outputs.is_empty(), "The model has no output");
+
+  let input = &inputs[0];
+  // UVR exports name it `output`, fall back to the first one otherwise
+  let output_index = outputs.iter().position(|o| o.name == "output").unwrap_or(0);
+  let output = &outputs[output_index];
+
+  let input_precision = Precision::from_tensor(input)?;
+  let output_precision = Precision::from_tensor(output)?;
+  let (input_dims, output_dims) = (input.dims.as_slice(), output.dims.as_slice());
+
+  check_dims(&input.name, input_dims, dim_f, dim_t)?;
+  check_dims(&output.name, output_dims, dim_f, dim_t)?;
+
+  let dim_f = dim_f
+    .or_else(|| static_dim(input_dims, 2))
+    .or_else(|| static_dim(output_dims, 2))
+    .ok_or_else(|| anyhow!("`dim_f` is not given and can not be derived from the model"))?;
+
+  let frames = static_dim(input_dims, 3).or_else(|| static_dim(output_dims, 3));
+  let dim_t = match (dim_t, frames) {
+    (Some(dim_t), _) => dim_t,
+    (None, Some(frames)) if frames.is_power_of_two() => frames.trailing_zeros() as u8,
+    (None, Some(frames)) => bail!("The model has {frames} frames, which is not a power of 2"),
+    (None, None) => bail!("`dim_t` is not given and can not be derived from the model"),
+  };
+
+  Ok(ModelIo {
+    input_name: input.name.clone(),
+    input_precision,
+    output_name: output.name.clone(),
+    output_index,
+    output_precision,
+    dim_f,
+    dim_t,
+  })
+}
@@ -0,0 +1,119 @@
+use std::{fs, io, path::Path, str::FromStr};
+
+use anyhow::{Context, Result};
+
+use super::proto;
+use crate::Error;
+
+pub const N_FFT: &str = "n_fft";
+pub const HOP_LENGTH: &str = "hop_length";
+pub const DIM_F: &str = "dim_f";
+pub const DIM_T: &str = "dim_t";
+pub const COMPENSATE: &str = "compensate";
+pub const PRIMARY_STEM: &str = "primary_stem";
+pub const SECONDARY_STEM: &str = "secondary_stem";
+pub const SAMPLE_RATE: &str = "sample_rate";
+
+// Separation parameters embedded in the custom metadata map of the model
+#[derive(Default)]
+pub struct MdxMetadata {
+  pub n_fft: Option<usize>,
+  pub hop_length: Option<usize>,
+  pub dim_f: Option<usize>,
+  pub dim_t: Option<u8>,
+  pub compensate: Option<f64>,
+  pub primary_stem: Option<String>,
+  pub secondary_stem: Option<String>,
+  pub sample_rate: Option<u32>,
+}
+
+fn parse<T: FromStr>(props: &[(String, String)], key: &str) -> Result<Option<T>>
+where
+  T::Err: std::error::Error + Send + Sync + 'static,
+{
+  get(props, key)
+    .map(|v| v.trim().parse())
+    .transpose()
+    .with_context(|| format!("Invalid value of metadata `{key}`"))
+}
+
+fn get(props: &[(String, String)], key: &str) -> Option<String> {
+  props
+    .iter()
+    .rev()
+    .find(|(k, _)| k == key)
+    .map(|(_, v)| v.clone())
+}
+
+impl MdxMetadata {
+  // `model` is the content of the ONNX model
+  pub fn read(model: &[u8]) -> crate::Result<Self> {
+    Self::parse(model).map_err(Error::InvalidModel)
+  }
+
+  fn parse(model: &[u8]) -> Result<Self> {
+    let props = proto::metadata_props(model)?;
+
+    Ok(Self {
+      n_fft: parse(&props, N_FFT)?,
+      hop_length: parse(&props, HOP_LENGTH)?,
+      dim_f: parse(&props, DIM_F)?,
+      dim_t: parse(&props, DIM_T)?,
+      compensate: parse(&props, COMPENSATE)?,
+      primary_stem: get(&props, PRIMARY_STEM),
+      secondary_stem: get(&props, SECONDARY_STEM),
+      sample_rate: parse(&props, SAMPLE_RATE)?,
+    })
+  }
+
+  pub fn props(&self) -> Vec<(&'static str, String)> {
+    let mut res = Vec::new();
+
+    macro_rules! push_props {
+      ($(($key:ident, $field:ident)),*) => {
+        $(
+          if let Some(v) = &self.$field {
+            res.push(($key, v.to_string()));
+          }
+        )*
+      };
+    }
+
+    push_props!(
+      (N_FFT, n_fft),
+      (HOP_LENGTH, hop_length),
+      (DIM_F, dim_f),
+      (DIM_T, dim_t),
+      (COMPENSATE, compensate),
+      (PRIMARY_STEM, primary_stem),
+      (SECONDARY_STEM, secondary_stem),
+      (SAMPLE_RATE, sample_rate)
+    );
+
+    res
+  }
+
+  // Write the parameters into `metadata_props` of the ONNX model,
+  // the existing props with the same keys will be replaced
+  #[tracing::instrument(skip_all)]
+  pub fn annotate(&self, input: impl AsRef<Path>, output: impl AsRef<Path>) -> crate::Result<()> {
+    let (input, output) = (input.as_ref(), output.as_ref());
+    let model = read_model(input)?;
+    let props = self.props();
+
+    let res = proto::replace_metadata_props(&model, &props).map_err(Error::InvalidModel)?;
+    fs::write(output, res)?;
+
+    tracing::info!(?output, props = props.len(), "Model annotated");
+
+    Ok(())
+  }
+}
+
+// Reads the content of the ONNX model at `path`
+pub fn read_model(path: &Path) -> crate::Result<Vec<u8>> {
+  fs::read(path).map_err(|err| match err.kind() {
+    io::ErrorKind::NotFound => Error::ModelNotFound(path.to_path_buf()),
+    _ => err.into(),
+  })
+}
@@ -1,118 +1,214 @@
+mod buffer;
 mod config;
+mod engine;
+mod high_end;
+mod io;
+mod metadata;
 mod preset;
+mod proto;
 mod stft;
+mod stream;
 
-use std::ops::{AddAssign, MulAssign};
-
-use anyhow::Result;
 use ndarray::{concatenate, prelude::*};
-use ort::Session;
 
-use crate::utils::hann_window;
+use crate::{
+  audio::{AudioBuffer, ChannelLayout},
+  cancel::CancelToken,
+  config::Backend,
+  error::{Error, Result},
+  progress::ProgressObserver,
+  Separator, SeparatorStream, Stems,
+};
+use buffer::ChunkBuffers;
 pub use config::{MdxConfig, MdxType};
+use engine::Engine;
+pub use high_end::HighEnd;
+pub use metadata::MdxMetadata;
 pub use preset::MDX_PRESETS;
 use stft::Stft;
+pub use stream::{MdxBlock, MdxStream};
+
+// Inference engines compiled into this build
+pub const ENGINES: &[&str] = &[
+  "onnxruntime",
+  #[cfg(feature = "tract")]
+  "tract",
+];
 
 pub struct MdxSeperator {
   n_fft: usize,
+  hop_length: usize,
+  sample_rate: u32,
   segment_size: usize,
+  dim_f: usize,
+  high_end: HighEnd,
+  backend: Backend,
   stft: Stft,
-  model: Session,
+  // the buffers `i` are made and run by `engines[i % engines.len()]`
+  engines: Vec<Engine>,
+  workers: usize,
+  pipeline: bool,
+  // hann window of a whole chunk
+  window: Array1<f64>,
   compensate: f64,
+  primary_stem: String,
+  secondary_stem: String,
 }
 
 impl MdxSeperator {
-  pub fn demix(&self, mix: ArrayView2<f64>) -> Result<Array2<f64>> {
-    tracing::info!("Start seperating...");
-
-    let (_, length) = mix.dim();
-
-    let trim = self.n_fft / 2;
-    let chunk_size = 1024 * (self.segment_size - 1);
-    let gen_size = chunk_size - 2 * trim;
-    let pad = gen_size + trim - (length % gen_size);
-
-    let mixture = concatenate(
-      Axis(1),
-      &[
-        Array2::zeros((2, trim)).view(),
-        mix.view(),
-        Array2::zeros((2, pad)).view(),
-      ],
-    )?;
+  // The first registered backend, which gets the first chance to run the nodes of the model
+  pub fn backend(&self) -> Backend {
+    self.backend
+  }
 
-    let step = chunk_size - self.n_fft;
+  pub fn primary_stem(&self) -> &str {
+    &self.primary_stem
+  }
 
-    let new_len = trim + length + pad;
+  pub fn secondary_stem(&self) -> &str {
+    &self.secondary_stem
+  }
 
-    let mut result: Array3<f64> = Array3::zeros((1, 2, new_len));
-    let mut divider: Array3<f64> = Array3::zeros((1, 2, new_len));
+  // Frames of the mixture given to the model at once
+  fn chunk_size(&self) -> usize {
+    self.hop_length * (self.segment_size - 1)
+  }
 
-    let total_chunks = (new_len - 1) / step + 1;
+  // Separates the mixture fed block by block, see `MdxStream`
+  pub fn stream<'a>(
+    &'a self,
+    progress: &'a dyn ProgressObserver,
+    cancel: &'a CancelToken,
+  ) -> Result<MdxStream<'a>> {
+    MdxStream::new(self, progress, cancel)
+  }
 
-    for i in (0..new_len).step_by(step) {
-      let start = i;
-      let end = (i + chunk_size).min(new_len);
+  // The buffers `index` of a chunk, with the tensors of its engine
+  fn make_buffers(&self, index: usize) -> Result<ChunkBuffers<'_>> {
+    let shape = (1, 4, self.dim_f, self.segment_size);
+    let engine = index % self.engines.len();
+    let tensors = self.engines[engine]
+      .tensors(shape)
+      .map_err(Error::BackendInit)?;
+
+    Ok(ChunkBuffers::new(
+      &self.stft,
+      tensors,
+      engine,
+      self.high_end,
+      self.compensate,
+      shape,
+      (1, 2, self.chunk_size()),
+    ))
+  }
 
-      let actual_size = end - start;
-      let cur_chunk = start / step + 1;
+  // Returns the primary stem of the whole mixture
+  pub fn demix(
+    &self,
+    mix: ArrayView2<f64>,
+    progress: &dyn ProgressObserver,
+    cancel: &CancelToken,
+  ) -> Result<Array2<f64>> {
+    tracing::info!("Start seperating...");
 
-      tracing::info!(
-        "{:.2}% Processing... ({cur_chunk}/{total_chunks})",
-        cur_chunk as f64 * 100.0 / total_chunks as f64
-      );
+    let mut stream = self.stream(progress, cancel)?.with_length(mix.ncols());
+    let head = stream.push(mix)?;
+    let tail = stream.finish()?;
 
-      let window = {
-        let window = hann_window(actual_size, false);
+    concatenate(Axis(1), &[head.primary.view(), tail.primary.view()])
+      .map_err(|err| Error::Dsp(err.into()))
+  }
 
-        let mut res = Array3::zeros((1, 2, actual_size));
-        res.slice_mut(s![.., 0, ..]).assign(&window);
-        res.slice_mut(s![.., 1, ..]).assign(&window);
-        res
-      };
+  // Returns the first `length` samples reconstructed from the chunk
+  fn run_model<'a>(
+    &self,
+    mix: ArrayView3<f64>,
+    length: usize,
+    buffers: &'a mut ChunkBuffers,
+  ) -> Result<ArrayViewMut3<'a, f64>> {
+    buffers.load_input(&self.stft, mix)?;
+    self.infer(buffers)?;
+
+    buffers.inverse(&self.stft, length)
+  }
 
-      let mut mix_part = mixture.slice(s![.., start..end]).to_owned();
+  // Runs the engine which made the buffers on the input buffer
+  fn infer(&self, buffers: &mut ChunkBuffers) -> Result<()> {
+    self.engines[buffers.engine()]
+      .run(buffers)
+      .map_err(Error::Inference)
+  }
+}
 
-      if end != i + chunk_size {
-        let pad_size = i + chunk_size - end;
-        mix_part.append(Axis(1), Array2::zeros((2, pad_size)).view())?;
-      }
+#[cfg(test)]
+impl MdxSeperator {
+  // A small separator running the stub engine
+  fn stub(workers: usize, pipeline: bool) -> Self {
+    let (n_fft, hop_length, segment_size) = (256, 64, 32);
+
+    Self {
+      n_fft,
+      hop_length,
+      sample_rate: 44100,
+      segment_size,
+      dim_f: 128,
+      high_end: HighEnd::Cut,
+      backend: Backend::CPU,
+      stft: Stft::new(n_fft, hop_length, 1).unwrap(),
+      engines: vec![Engine::Stub],
+      workers,
+      pipeline,
+      window: crate::utils::hann_window(hop_length * (segment_size - 1), false),
+      compensate: 1.0,
+      primary_stem: "Vocals".to_owned(),
+      secondary_stem: "Instrumental".to_owned(),
+    }
+  }
+}
 
-      let mut tar_waves = self.run_model(mix_part.insert_axis(Axis(0)).view())?;
+impl Separator for MdxSeperator {
+  fn sample_rate(&self) -> u32 {
+    self.sample_rate
+  }
 
-      tar_waves
-        .slice_mut(s![.., .., ..actual_size])
-        .mul_assign(&window);
+  fn stem_names(&self) -> Vec<&str> {
+    vec![&self.primary_stem, &self.secondary_stem]
+  }
 
-      divider
-        .slice_mut(s![.., .., start..end])
-        .add_assign(&window);
+  // The secondary stem is what remains of the mixture
+  fn separate_with(
+    &self,
+    mix: &AudioBuffer,
+    progress: &dyn ProgressObserver,
+    cancel: &CancelToken,
+  ) -> Result<Stems> {
+    let mix = mix.conform(self.sample_rate, ChannelLayout::Stereo, progress)?;
 
-      result
-        .slice_mut(s![.., .., start..end])
-        .add_assign(&tar_waves.slice(s![.., .., ..actual_size]));
-    }
+    let primary = self.demix(mix.samples(), progress, cancel)?;
+    let secondary = &mix.samples() - &primary;
 
-    let tar_waves = result / divider;
-    let tar_waves = tar_waves.remove_axis(Axis(0));
+    let stem = |wave| -> Result<_> {
+      Ok(AudioBuffer::new(wave, self.sample_rate)?.with_metadata(mix.metadata().clone()))
+    };
 
-    let right = (new_len - trim).min(trim + length);
-    let tar_waves = tar_waves.slice(s![.., trim..right]);
+    let mut stems = Stems::new();
+    stems.push(&self.primary_stem, stem(primary)?);
+    stems.push(&self.secondary_stem, stem(secondary)?);
 
-    Ok(tar_waves.mapv(|x| x * self.compensate))
+    Ok(stems)
   }
 
-  fn run_model(&self, mix: ArrayView3<f64>) -> Result<Array3<f64>> {
-    let mut spek = self.stft.apply(mix)?;
-    spek.slice_mut(s![.., .., ..3, ..]).fill(0.0);
-
-    let spec_pred = self.model.run(ort::inputs![spek.mapv(|x| x as f32)]?)?;
-    let spec_pred: Array4<f32> = spec_pred["output"]
-      .extract_tensor::<f32>()?
-      .view()
-      .to_owned()
-      .into_dimensionality()?;
+  fn separate_stream<'a>(
+    &'a self,
+    length: Option<usize>,
+    progress: &'a dyn ProgressObserver,
+    cancel: &'a CancelToken,
+  ) -> Result<Box<dyn SeparatorStream + 'a>> {
+    let mut stream = self.stream(progress, cancel)?;
+    if let Some(length) = length {
+      stream = stream.with_length(length);
+    }
 
-    Ok(self.stft.inverse(spec_pred.mapv(|x| x.into()).view())?)
+    Ok(Box::new(stream))
   }
 }
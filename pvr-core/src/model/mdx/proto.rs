@@ -0,0 +1,262 @@
+// Just enough protobuf to read the I/O of an ONNX model
+// and edit `ModelProto.metadata_props` in place
+use anyhow::{bail, Result};
+
+use super::io::TensorInfo;
+
+// `ModelProto.graph` and `ModelProto.metadata_props`
+const GRAPH: u64 = 7;
+const METADATA_PROPS: u64 = 14;
+// `StringStringEntryProto.key` and `StringStringEntryProto.value`
+const KEY: u64 = 1;
+const VALUE: u64 = 2;
+// `GraphProto.initializer`, `GraphProto.input` and `GraphProto.output`
+const INITIALIZER: u64 = 5;
+const INPUT: u64 = 11;
+const OUTPUT: u64 = 12;
+// `TensorProto.name`
+const TENSOR_NAME: u64 = 8;
+// `ValueInfoProto.name` and `ValueInfoProto.type`
+const VALUE_NAME: u64 = 1;
+const VALUE_TYPE: u64 = 2;
+// `TypeProto.tensor_type`
+const TENSOR_TYPE: u64 = 1;
+// `TypeProto.Tensor.elem_type` and `TypeProto.Tensor.shape`
+const ELEM_TYPE: u64 = 1;
+const SHAPE: u64 = 2;
+// `TensorShapeProto.dim` and `TensorShapeProto.Dimension.dim_value`
+const DIM: u64 = 1;
+const DIM_VALUE: u64 = 1;
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_I64: u64 = 1;
+const WIRE_LEN: u64 = 2;
+const WIRE_I32: u64 = 5;
+
+fn read_varint(buf: &[u8], pos: &mut usize) -> Result<u64> {
+  let mut res = 0;
+  for shift in (0..64).step_by(7) {
+    let Some(&byte) = buf.get(*pos) else {
+      bail!("Unexpected end of varint");
+    };
+    *pos += 1;
+    res |= u64::from(byte & 0x7f) << shift;
+    if byte & 0x80 == 0 {
+      return Ok(res);
+    }
+  }
+  bail!("Varint is too long")
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+  while value >= 0x80 {
+    buf.push((value as u8 & 0x7f) | 0x80);
+    value >>= 7;
+  }
+  buf.push(value as u8);
+}
+
+fn write_bytes(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+  write_varint(buf, field << 3 | WIRE_LEN);
+  write_varint(buf, bytes.len() as u64);
+  buf.extend_from_slice(bytes);
+}
+
+// Returns `(field, payload, whole field)` of every top level field
+fn fields(buf: &[u8]) -> Result<Vec<(u64, &[u8], &[u8])>> {
+  let mut res = Vec::new();
+  let mut pos = 0;
+
+  while pos < buf.len() {
+    let start = pos;
+    let tag = read_varint(buf, &mut pos)?;
+    let (payload_start, len) = match tag & 7 {
+      WIRE_VARINT => {
+        let payload_start = pos;
+        read_varint(buf, &mut pos)?;
+        (payload_start, pos - payload_start)
+      }
+      WIRE_I64 => (pos, 8),
+      WIRE_LEN => {
+        let len = read_varint(buf, &mut pos)?;
+        (pos, usize::try_from(len)?)
+      }
+      WIRE_I32 => (pos, 4),
+      wire => bail!("Unsupported wire type {wire}"),
+    };
+
+    // the lengths come from the file, which may be truncated or malformed
+    let Some(end) = payload_start
+      .checked_add(len)
+      .filter(|&end| end <= buf.len())
+    else {
+      bail!("Unexpected end of field");
+    };
+    pos = end;
+
+    res.push((tag >> 3, &buf[payload_start..pos], &buf[start..pos]));
+  }
+
+  Ok(res)
+}
+
+fn find(buf: &[u8], field: u64) -> Result<Option<&[u8]>> {
+  Ok(
+    fields(buf)?
+      .into_iter()
+      .find(|(f, _, _)| *f == field)
+      .map(|(_, payload, _)| payload),
+  )
+}
+
+fn varint(payload: &[u8]) -> Result<u64> {
+  read_varint(payload, &mut 0)
+}
+
+fn string(payload: &[u8]) -> String {
+  String::from_utf8_lossy(payload).into_owned()
+}
+
+pub fn metadata_props(model: &[u8]) -> Result<Vec<(String, String)>> {
+  let mut res = Vec::new();
+
+  for (field, payload, _) in fields(model)? {
+    if field != METADATA_PROPS {
+      continue;
+    }
+    if let Some(key) = find(payload, KEY)? {
+      let value = find(payload, VALUE)?.unwrap_or_default();
+      res.push((string(key), string(value)));
+    }
+  }
+
+  Ok(res)
+}
+
+pub fn replace_metadata_props(model: &[u8], props: &[(&str, String)]) -> Result<Vec<u8>> {
+  let mut res = Vec::with_capacity(model.len());
+
+  for (field, payload, raw) in fields(model)? {
+    if field == METADATA_PROPS {
+      if let Some(key) = find(payload, KEY)? {
+        if props.iter().any(|(k, _)| k.as_bytes() == key) {
+          continue;
+        }
+      }
+    }
+    res.extend_from_slice(raw);
+  }
+
+  for (key, value) in props {
+    let mut entry = Vec::new();
+    write_bytes(&mut entry, KEY, key.as_bytes());
+    write_bytes(&mut entry, VALUE, value.as_bytes());
+    write_bytes(&mut res, METADATA_PROPS, &entry);
+  }
+
+  Ok(res)
+}
+
+// Symbolic and missing dimensions are -1
+fn tensor_info(value_info: &[u8]) -> Result<TensorInfo> {
+  let name = find(value_info, VALUE_NAME)?
+    .map(string)
+    .unwrap_or_default();
+  let tensor = match find(value_info, VALUE_TYPE)? {
+    Some(ty) => find(ty, TENSOR_TYPE)?,
+    None => None,
+  };
+  let Some(tensor) = tensor else {
+    bail!("`{name}` is not a tensor");
+  };
+
+  let elem_type = find(tensor, ELEM_TYPE)?
+    .map(varint)
+    .transpose()?
+    .unwrap_or(0) as i32;
+
+  let mut dims = Vec::new();
+  if let Some(shape) = find(tensor, SHAPE)? {
+    for (field, dim, _) in fields(shape)? {
+      if field != DIM {
+        continue;
+      }
+      let value = find(dim, DIM_VALUE)?.map(varint).transpose()?;
+      dims.push(value.map_or(-1, |v| v as i64));
+    }
+  }
+
+  Ok(TensorInfo {
+    name,
+    elem_type,
+    dims,
+  })
+}
+
+// The inputs and outputs of the main graph, initializers listed
+// as inputs by old exporters are left out
+pub fn graph_io(model: &[u8]) -> Result<(Vec<TensorInfo>, Vec<TensorInfo>)> {
+  let Some(graph) = find(model, GRAPH)? else {
+    bail!("The model has no graph");
+  };
+  let fields = fields(graph)?;
+
+  let mut initializers = Vec::new();
+  for (field, payload, _) in &fields {
+    if *field == INITIALIZER {
+      if let Some(name) = find(payload, TENSOR_NAME)? {
+        initializers.push(name);
+      }
+    }
+  }
+
+  let (mut inputs, mut outputs) = (Vec::new(), Vec::new());
+  for (field, payload, _) in &fields {
+    match *field {
+      INPUT => {
+        let name = find(payload, VALUE_NAME)?.unwrap_or_default();
+        if !initializers.contains(&name) {
+          inputs.push(tensor_info(payload)?);
+        }
+      }
+      OUTPUT => outputs.push(tensor_info(payload)?),
+      _ => {}
+    }
+  }
+
+  Ok((inputs, outputs))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn length_past_the_end() {
+    // field 1 of 16 bytes, with only 2 of them present
+    assert!(fields(&[0x0a, 0x10, 0x00, 0x00]).is_err());
+    // field 1 of 8 bytes in a fixed64
+    assert!(fields(&[0x09, 0x00, 0x00]).is_err());
+    // field 1 of 4 bytes in a fixed32
+    assert!(fields(&[0x0d, 0x00]).is_err());
+  }
+
+  #[test]
+  fn length_overflow() {
+    // field 1 of `u64::MAX` bytes
+    let mut buf = vec![0x0a];
+    write_varint(&mut buf, u64::MAX);
+    assert!(fields(&buf).is_err());
+  }
+
+  #[test]
+  fn metadata_round_trip() {
+    let model = replace_metadata_props(&[], &[("n_fft", "6144".to_owned())]).unwrap();
+    let model = replace_metadata_props(&model, &[("n_fft", "7680".to_owned())]).unwrap();
+
+    assert_eq!(
+      metadata_props(&model).unwrap(),
+      vec![("n_fft".to_owned(), "7680".to_owned())]
+    );
+  }
+}
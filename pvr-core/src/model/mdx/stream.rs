@@ -0,0 +1,637 @@
+use std::{
+  borrow::Cow,
+  collections::BTreeMap,
+  mem,
+  ops::{AddAssign, MulAssign},
+  panic::{self, AssertUnwindSafe},
+  sync::{
+    mpsc::{self, Sender},
+    Mutex,
+  },
+  thread,
+};
+
+use anyhow::anyhow;
+use ndarray::{concatenate, prelude::*};
+
+use super::{buffer::ChunkBuffers, MdxSeperator};
+use crate::{
+  cancel::CancelToken,
+  error::{ensure_as, Error, Result},
+  progress::{Progress, ProgressObserver, Stage},
+  utils::hann_window,
+  SeparatorStream,
+};
+
+// Chunks the pipeline waits for before running, it is filled and drained once
+// per batch, so a longer batch keeps the stages busy for more of the time
+const PIPELINE_BATCH: usize = 8;
+// One for each of the STFT, the inference and the iSTFT, and one for the overlap-add
+const PIPELINE_BUFFERS: usize = 4;
+
+// The index and the size of a chunk, its mixture padded to a whole chunk,
+// and the buffers it is run with
+type Job<'s> = (usize, usize, Array2<f64>, ChunkBuffers<'s>);
+// The index of a chunk and its buffers holding the windowed prediction
+type Done<'s> = (usize, ChunkBuffers<'s>);
+
+// Finished frames of both stems, the secondary one is what remains of the mixture
+#[derive(Clone, Debug)]
+pub struct MdxBlock {
+  pub primary: Array2<f64>,
+  pub secondary: Array2<f64>,
+}
+
+impl MdxBlock {
+  fn new() -> Self {
+    Self {
+      primary: Array2::zeros((2, 0)),
+      secondary: Array2::zeros((2, 0)),
+    }
+  }
+
+  pub fn frames(&self) -> usize {
+    self.primary.ncols()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.frames() == 0
+  }
+
+  // Both stems in the order of `stem_names`, none if there are no frames
+  pub fn into_stems(self) -> Vec<Array2<f64>> {
+    if self.is_empty() {
+      Vec::new()
+    } else {
+      vec![self.primary, self.secondary]
+    }
+  }
+
+  fn append(&mut self, primary: ArrayView2<f64>, secondary: ArrayView2<f64>) -> Result<()> {
+    self
+      .primary
+      .append(Axis(1), primary)
+      .and_then(|()| self.secondary.append(Axis(1), secondary))
+      .map_err(|err| Error::Dsp(err.into()))
+  }
+}
+
+// Separates the mixture block by block. Only the chunks of the mixture being run
+// and one chunk of the overlap-add are kept, so the memory does not grow with the
+// length of the song. The frames are the same as `demix` however the chunks are
+// run, and lag behind the input by up to one chunk per worker, as the workers
+// wait until each of them has a chunk to run, or by a batch of the pipeline
+pub struct MdxStream<'a> {
+  separator: &'a MdxSeperator,
+  progress: &'a dyn ProgressObserver,
+  cancel: &'a CancelToken,
+  // one per worker, or per stage of the pipeline
+  buffers: Vec<ChunkBuffers<'a>>,
+  // the mixture padded with `n_fft / 2` zeros in front, from `start` on
+  // is the next chunk, the sums of the overlap-add begin at the same frame
+  input: Array2<f64>,
+  start: usize,
+  result: Array2<f64>,
+  divider: Array1<f64>,
+  // padding in front which is not emitted yet
+  skip: usize,
+  received: usize,
+  emitted: usize,
+  chunks: u64,
+  total_chunks: Option<u64>,
+}
+
+impl<'a> MdxStream<'a> {
+  pub(super) fn new(
+    separator: &'a MdxSeperator,
+    progress: &'a dyn ProgressObserver,
+    cancel: &'a CancelToken,
+  ) -> Result<Self> {
+    let trim = separator.n_fft / 2;
+    let chunk_size = separator.chunk_size();
+    let buffer_count = if separator.pipeline {
+      PIPELINE_BUFFERS
+    } else {
+      separator.workers
+    };
+
+    Ok(Self {
+      separator,
+      progress,
+      cancel,
+      buffers: (0..buffer_count)
+        .map(|index| separator.make_buffers(index))
+        .collect::<Result<_>>()?,
+      input: Array2::zeros((2, trim)),
+      start: 0,
+      result: Array2::zeros((2, chunk_size)),
+      divider: Array1::zeros(chunk_size),
+      skip: trim,
+      received: 0,
+      emitted: 0,
+      chunks: 0,
+      total_chunks: None,
+    })
+  }
+
+  // The length of the whole mixture if known, only used to report the progress
+  pub fn with_length(mut self, frames: usize) -> Self {
+    let (trim, step) = (self.separator.n_fft / 2, self.step());
+    let padded = trim + frames + (step + trim - frames % step);
+    self.total_chunks = Some(((padded - 1) / step + 1) as u64);
+    self
+  }
+
+  // Frames of the mixture between the starts of two chunks
+  fn step(&self) -> usize {
+    self.separator.chunk_size() - self.separator.n_fft
+  }
+
+  // `block` is stereo at the sample rate of the model, the frames of the stems
+  // finished by it are returned, which may be none
+  pub fn push(&mut self, block: ArrayView2<f64>) -> Result<MdxBlock> {
+    ensure_as!(
+      Audio,
+      block.nrows() == 2,
+      "The mixture should be stereo, got {} channels",
+      block.nrows()
+    );
+
+    // drop the frames before the next chunk, which only happens once per batch,
+    // appending the blocks in between is amortized
+    if self.start > 0 {
+      self.input = self.input.slice(s![.., self.start..]).to_owned();
+      self.start = 0;
+    }
+    self
+      .input
+      .append(Axis(1), block)
+      .map_err(|err| Error::Dsp(err.into()))?;
+    self.received += block.ncols();
+
+    let (chunk_size, step) = (self.separator.chunk_size(), self.step());
+    let left = self.input.ncols() - self.start;
+    let ready = if left < chunk_size {
+      0
+    } else {
+      (left - chunk_size) / step + 1
+    };
+
+    // wait until every worker has a whole chunk to run, or until there are
+    // enough chunks to keep the stages of the pipeline busy
+    let chunks = if self.separator.pipeline {
+      if ready >= PIPELINE_BATCH {
+        ready
+      } else {
+        0
+      }
+    } else {
+      ready / self.separator.workers * self.separator.workers
+    };
+
+    let mut res = MdxBlock::new();
+    if chunks > 0 {
+      self.run_chunks(&vec![chunk_size; chunks], &mut res)?;
+    }
+
+    Ok(res)
+  }
+
+  // Pads the end of the mixture like `demix` and runs the chunks left
+  pub fn finish(mut self) -> Result<MdxBlock> {
+    let (trim, step) = (self.separator.n_fft / 2, self.step());
+    let pad = step + trim - self.received % step;
+
+    self.input = concatenate(
+      Axis(1),
+      &[
+        self.input.slice(s![.., self.start..]),
+        Array2::zeros((2, pad)).view(),
+      ],
+    )
+    .map_err(|err| Error::Dsp(err.into()))?;
+    self.start = 0;
+
+    // the chunks reaching the end are cut short
+    let (chunk_size, left) = (self.separator.chunk_size(), self.input.ncols());
+    let sizes: Vec<_> = (0..left)
+      .step_by(step)
+      .map(|offset| (left - offset).min(chunk_size))
+      .collect();
+
+    let mut res = MdxBlock::new();
+    self.run_chunks(&sizes, &mut res)?;
+
+    Ok(res)
+  }
+
+  fn report(&self) {
+    self.progress.on_progress(Progress::new(
+      Stage::Separate,
+      self.chunks,
+      self.total_chunks,
+    ));
+  }
+
+  // Runs a chunk of each size, the first at `start` and the others one step
+  // apart. The predictions are added to the overlap-add in order, so the result
+  // does not depend on how the chunks are run
+  fn run_chunks(&mut self, sizes: &[usize], res: &mut MdxBlock) -> Result<()> {
+    if self.chunks == 0 {
+      self.report();
+    }
+
+    // the buffers go with the chunks to the threads and back
+    let mut buffers = mem::take(&mut self.buffers);
+    let ran = if self.separator.pipeline {
+      self.run_pipeline(&mut buffers, sizes, res)
+    } else {
+      self.run_workers(&mut buffers, sizes, res)
+    };
+    self.buffers = buffers;
+
+    ran
+  }
+
+  // Each worker takes the next chunk from a shared queue once it is done with
+  // its last one, so a slow chunk does not hold the others back. Every chunk
+  // taken is sent back, even if it failed, so it is never waited for in vain
+  fn run_workers(
+    &mut self,
+    buffers: &mut Vec<ChunkBuffers<'a>>,
+    sizes: &[usize],
+    res: &mut MdxBlock,
+  ) -> Result<()> {
+    let (separator, cancel) = (self.separator, self.cancel);
+    let threads = separator.workers.min(sizes.len());
+
+    let (job_tx, job_rx) = mpsc::channel::<Job<'a>>();
+    let (done_tx, done_rx) = mpsc::channel::<Result<Done<'a>>>();
+    let job_rx = Mutex::new(job_rx);
+
+    thread::scope(|s| {
+      for _ in 0..threads {
+        let (job_rx, done_tx) = (&job_rx, done_tx.clone());
+
+        s.spawn(move || loop {
+          // the lock is only held while waiting for the next chunk
+          let job = job_rx.lock().expect("The queue is poisoned").recv();
+          let Ok(job) = job else {
+            break;
+          };
+
+          let done = panic::catch_unwind(AssertUnwindSafe(|| predict(separator, job, cancel)));
+          match done {
+            Ok(done) => {
+              let failed = done.is_err();
+              if done_tx.send(done).is_err() || failed {
+                break;
+              }
+            }
+            Err(payload) => {
+              let _ = done_tx.send(Err(Error::Inference(anyhow!("A worker panicked"))));
+              panic::resume_unwind(payload);
+            }
+          }
+        });
+      }
+      drop(done_tx);
+
+      let stopped = || Error::Inference(anyhow!("The workers stopped early"));
+      self.feed_chunks(
+        buffers,
+        sizes,
+        job_tx,
+        || done_rx.recv().map_err(|_| stopped()).and_then(|done| done),
+        res,
+      )
+    })
+  }
+
+  // The STFT, the inference and the iSTFT run on their own threads, connected
+  // by queues, so the STFT of the next chunk and the iSTFT of the previous one
+  // run while the model runs on a chunk. Each chunk takes a free buffer here,
+  // goes through the stages in order and comes back for the overlap-add
+  fn run_pipeline(
+    &mut self,
+    buffers: &mut Vec<ChunkBuffers<'a>>,
+    sizes: &[usize],
+    res: &mut MdxBlock,
+  ) -> Result<()> {
+    let (separator, cancel) = (self.separator, self.cancel);
+
+    let (mix_tx, mix_rx) = mpsc::channel::<Job<'a>>();
+    let (spec_tx, spec_rx) = mpsc::channel::<(usize, usize, ChunkBuffers<'a>)>();
+    let (pred_tx, pred_rx) = mpsc::channel::<(usize, usize, ChunkBuffers<'a>)>();
+    let (wave_tx, wave_rx) = mpsc::channel::<Done<'a>>();
+
+    thread::scope(|s| {
+      // a stage which finds the next one gone stops, the error is taken from the join
+      let forward = s.spawn(move || -> Result<()> {
+        for (index, size, mix, mut buffers) in mix_rx {
+          cancel.check()?;
+          buffers.load_input(&separator.stft, mix.insert_axis(Axis(0)).view())?;
+          if spec_tx.send((index, size, buffers)).is_err() {
+            break;
+          }
+        }
+        Ok(())
+      });
+
+      let inference = s.spawn(move || -> Result<()> {
+        for (index, size, mut buffers) in spec_rx {
+          separator.infer(&mut buffers)?;
+          if pred_tx.send((index, size, buffers)).is_err() {
+            break;
+          }
+        }
+        Ok(())
+      });
+
+      let inverse = s.spawn(move || -> Result<()> {
+        for (index, size, mut buffers) in pred_rx {
+          buffers
+            .inverse(&separator.stft, size)?
+            .mul_assign(&*chunk_window(&separator.window, size));
+          if wave_tx.send((index, buffers)).is_err() {
+            break;
+          }
+        }
+        Ok(())
+      });
+
+      let stopped = || Error::Inference(anyhow!("The pipeline stopped early"));
+      let fed = self.feed_chunks(
+        buffers,
+        sizes,
+        mix_tx,
+        || wave_rx.recv().map_err(|_| stopped()),
+        res,
+      );
+
+      // the failure of a stage is the cause of the queues being closed
+      [forward, inference, inverse]
+        .map(|handle| handle.join().expect("A stage of the pipeline panicked"))
+        .into_iter()
+        .collect::<Result<()>>()
+        .and(fed)
+    })
+  }
+
+  // Queues the chunks while there are free buffers, and adds the predictions
+  // coming back to the overlap-add in order. The ones coming back early keep
+  // their buffers until the chunks before them are added
+  fn feed_chunks(
+    &mut self,
+    buffers: &mut Vec<ChunkBuffers<'a>>,
+    sizes: &[usize],
+    job_tx: Sender<Job<'a>>,
+    mut recv: impl FnMut() -> Result<Done<'a>>,
+    res: &mut MdxBlock,
+  ) -> Result<()> {
+    let (chunk_size, step) = (self.separator.chunk_size(), self.step());
+
+    // `start` moves on as the chunks are emitted, the offsets are taken from where it begins
+    let first = self.start;
+    let mut queued = 0;
+    let mut early = BTreeMap::new();
+
+    for (index, &size) in sizes.iter().enumerate() {
+      while queued < sizes.len() {
+        let Some(free) = buffers.pop() else {
+          break;
+        };
+
+        let offset = first + queued * step;
+        let mix = pad_chunk(
+          self.input.slice(s![.., offset..offset + sizes[queued]]),
+          chunk_size,
+        )?;
+        job_tx
+          .send((queued, sizes[queued], mix, free))
+          .map_err(|_| Error::Inference(anyhow!("The chunks are not taken anymore")))?;
+        queued += 1;
+      }
+
+      let done = loop {
+        if let Some(done) = early.remove(&index) {
+          break done;
+        }
+        let (done_index, done) = recv()?;
+        early.insert(done_index, done);
+      };
+
+      self.overlap_add(done.wave(size), size, res)?;
+      buffers.push(done);
+    }
+
+    Ok(())
+  }
+
+  // Adds the windowed prediction of the next chunk, which is `size` frames long,
+  // and emits the frames it finishes
+  fn overlap_add(
+    &mut self,
+    output: ArrayView3<f64>,
+    size: usize,
+    res: &mut MdxBlock,
+  ) -> Result<()> {
+    self
+      .divider
+      .slice_mut(s![..size])
+      .add_assign(&*chunk_window(&self.separator.window, size));
+    self
+      .result
+      .slice_mut(s![.., ..size])
+      .add_assign(&output.index_axis(Axis(0), 0));
+
+    self.chunks += 1;
+    self.report();
+
+    // the last chunk finishes all the frames left
+    let frames = self.step().min(self.input.ncols() - self.start);
+    self.emit(frames, res)
+  }
+
+  // The first `frames` of the overlap-add are finished, since the next chunk
+  // starts after them. The padding is dropped from both ends
+  fn emit(&mut self, frames: usize, res: &mut MdxBlock) -> Result<()> {
+    let skip = self.skip.min(frames);
+    let end = (skip + self.received - self.emitted).min(frames);
+    self.skip -= skip;
+
+    // the prediction is compensated in the spectrogram already
+    let primary = &self.result.slice(s![.., skip..end]) / &self.divider.slice(s![skip..end]);
+    let mix = self
+      .input
+      .slice(s![.., self.start + skip..self.start + end]);
+    let secondary = &mix - &primary;
+
+    res.append(primary.view(), secondary.view())?;
+    self.emitted += end - skip;
+    self.start += frames;
+
+    // move the sums of the next chunk to the front, in place
+    let chunk_size = self.result.ncols();
+    let not_contiguous = || Error::Dsp(anyhow!("The overlap-add is not contiguous"));
+    let result = self.result.as_slice_mut().ok_or_else(not_contiguous)?;
+    for row in result.chunks_exact_mut(chunk_size) {
+      shift_front(row, frames);
+    }
+    shift_front(
+      self.divider.as_slice_mut().ok_or_else(not_contiguous)?,
+      frames,
+    );
+
+    Ok(())
+  }
+}
+
+impl SeparatorStream for MdxStream<'_> {
+  fn push(&mut self, block: ArrayView2<f64>) -> Result<Vec<Array2<f64>>> {
+    MdxStream::push(self, block).map(MdxBlock::into_stems)
+  }
+
+  fn finish(self: Box<Self>) -> Result<Vec<Array2<f64>>> {
+    MdxStream::finish(*self).map(MdxBlock::into_stems)
+  }
+}
+
+// The window of a chunk of `size` frames, `window` is the one of a whole chunk
+fn chunk_window(window: &Array1<f64>, size: usize) -> Cow<'_, Array1<f64>> {
+  if size == window.len() {
+    Cow::Borrowed(window)
+  } else {
+    Cow::Owned(hann_window(size, false))
+  }
+}
+
+// Moves the values after the first `count` to the front and zeroes the rest
+fn shift_front(values: &mut [f64], count: usize) {
+  values.copy_within(count.., 0);
+  let kept = values.len() - count;
+  values[kept..].fill(0.0);
+}
+
+// The part of the mixture padded with zeros to a whole chunk, if it is cut by the end
+fn pad_chunk(mix: ArrayView2<f64>, chunk_size: usize) -> Result<Array2<f64>> {
+  let mut mix_part = mix.to_owned();
+
+  if mix.ncols() < chunk_size {
+    mix_part
+      .append(Axis(1), Array2::zeros((2, chunk_size - mix.ncols())).view())
+      .map_err(|err| Error::Dsp(err.into()))?;
+  }
+
+  Ok(mix_part)
+}
+
+// Runs the chunk on the engine of its buffers, the windowed prediction is left in them
+fn predict<'s>(
+  separator: &MdxSeperator,
+  (index, size, mix, mut buffers): Job<'s>,
+  cancel: &CancelToken,
+) -> Result<Done<'s>> {
+  cancel.check()?;
+
+  separator
+    .run_model(mix.insert_axis(Axis(0)).view(), size, &mut buffers)?
+    .mul_assign(&*chunk_window(&separator.window, size));
+
+  Ok((index, buffers))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::Separator;
+
+  fn mixture(length: usize) -> Array2<f64> {
+    Array2::from_shape_fn((2, length), |(row, i)| {
+      let t = (i + row * 13) as f64;
+      (t * 0.05).sin() + 0.5 * (t * 0.31).cos()
+    })
+  }
+
+  #[test]
+  fn odd_blocks_match_demix() {
+    let separator = MdxSeperator::stub(2, false);
+    let mix = mixture(12345);
+    let cancel = CancelToken::new();
+    let expected = separator.demix(mix.view(), &(), &cancel).unwrap();
+
+    // blocks shorter and longer than a chunk, and empty ones
+    let mut stream = separator.stream(&(), &cancel).unwrap();
+    let mut res = MdxBlock::new();
+    let (mut pos, mut sizes) = (0, [0, 1, 777, 0, 3001, 13, 5000].iter().cycle());
+    while pos < mix.ncols() {
+      let end = (pos + sizes.next().unwrap()).min(mix.ncols());
+      let block = stream.push(mix.slice(s![.., pos..end])).unwrap();
+      res
+        .append(block.primary.view(), block.secondary.view())
+        .unwrap();
+      pos = end;
+    }
+    let tail = stream.finish().unwrap();
+    res
+      .append(tail.primary.view(), tail.secondary.view())
+      .unwrap();
+
+    assert_eq!(res.primary, expected);
+    assert_eq!(res.secondary, &mix - &expected);
+  }
+
+  #[test]
+  fn workers_give_identical_stems() {
+    let mix = mixture(30000);
+    let cancel = CancelToken::new();
+    let expected = MdxSeperator::stub(1, false)
+      .demix(mix.view(), &(), &cancel)
+      .unwrap();
+
+    for workers in [2, 3, 8] {
+      let separator = MdxSeperator::stub(workers, false);
+      let res = separator.demix(mix.view(), &(), &cancel).unwrap();
+      assert_eq!(res, expected, "{workers} workers");
+    }
+  }
+
+  #[test]
+  fn pipeline_gives_identical_stems() {
+    // long enough for the pushes to run whole batches of the pipeline
+    let mix = mixture(30000);
+    let cancel = CancelToken::new();
+    let expected = MdxSeperator::stub(1, false)
+      .demix(mix.view(), &(), &cancel)
+      .unwrap();
+
+    let separator = MdxSeperator::stub(1, true);
+    assert_eq!(separator.demix(mix.view(), &(), &cancel).unwrap(), expected);
+  }
+
+  #[test]
+  fn separator_stream_matches_demix() {
+    let separator = MdxSeperator::stub(2, false);
+    let mix = mixture(12345);
+    let cancel = CancelToken::new();
+    let expected = separator.demix(mix.view(), &(), &cancel).unwrap();
+
+    let separator: &dyn Separator = &separator;
+    let mut stream = separator
+      .separate_stream(Some(mix.ncols()), &(), &cancel)
+      .unwrap();
+    let mut res = MdxBlock::new();
+    let mut append = |stems: Vec<Array2<f64>>| {
+      if let [primary, secondary] = stems.as_slice() {
+        res.append(primary.view(), secondary.view()).unwrap();
+      }
+    };
+    for block in mix.axis_chunks_iter(Axis(1), 4000) {
+      append(stream.push(block).unwrap());
+    }
+    append(stream.finish().unwrap());
+
+    assert_eq!(res.primary, expected);
+    assert_eq!(res.secondary, &mix - &expected);
+  }
+}
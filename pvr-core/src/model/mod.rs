@@ -1,2 +1,15 @@
+#[cfg(feature = "mdx")]
 pub mod mdx;
+mod separator;
+#[cfg(feature = "vr")]
 pub mod vr;
+
+pub use separator::{Separator, SeparatorStream, Stems};
+
+// Model families compiled into this build
+pub const MODEL_FAMILIES: &[&str] = &[
+  #[cfg(feature = "mdx")]
+  "mdx",
+  #[cfg(feature = "vr")]
+  "vr",
+];
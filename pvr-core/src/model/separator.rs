@@ -0,0 +1,139 @@
+use ndarray::prelude::*;
+
+use crate::{audio::AudioBuffer, cancel::CancelToken, progress::ProgressObserver, Error, Result};
+
+// Named waveforms, in the order given by the separator
+#[derive(Clone, Debug, Default)]
+pub struct Stems {
+  stems: Vec<(String, AudioBuffer)>,
+}
+
+impl Stems {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn push(&mut self, name: impl Into<String>, wave: AudioBuffer) {
+    self.stems.push((name.into(), wave));
+  }
+
+  pub fn get(&self, name: &str) -> Option<&AudioBuffer> {
+    self
+      .stems
+      .iter()
+      .find(|(n, _)| n == name)
+      .map(|(_, wave)| wave)
+  }
+
+  pub fn names(&self) -> impl Iterator<Item = &str> {
+    self.stems.iter().map(|(name, _)| name.as_str())
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = (&str, &AudioBuffer)> {
+    self.stems.iter().map(|(name, wave)| (name.as_str(), wave))
+  }
+
+  pub fn len(&self) -> usize {
+    self.stems.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.stems.is_empty()
+  }
+}
+
+impl IntoIterator for Stems {
+  type Item = (String, AudioBuffer);
+  type IntoIter = std::vec::IntoIter<(String, AudioBuffer)>;
+
+  fn into_iter(self) -> Self::IntoIter {
+    self.stems.into_iter()
+  }
+}
+
+// The interface shared by every model family
+pub trait Separator {
+  // The sample rate the model works at, the stems are at this rate
+  fn sample_rate(&self) -> u32;
+
+  // Names of the stems returned by `separate`, in order
+  fn stem_names(&self) -> Vec<&str>;
+
+  // The mixture is converted to the sample rate and channel layout of the model,
+  // and the stems keep its metadata
+  fn separate(&self, mix: &AudioBuffer) -> Result<Stems> {
+    self.separate_with(mix, &(), &CancelToken::new())
+  }
+
+  // Same as `separate`, reporting the resampling and every chunk to `progress`,
+  // and fails with `Error::Cancelled` between two chunks once `cancel` is cancelled
+  fn separate_with(
+    &self,
+    mix: &AudioBuffer,
+    progress: &dyn ProgressObserver,
+    cancel: &CancelToken,
+  ) -> Result<Stems>;
+
+  // Separates a stereo mixture at `sample_rate` fed block by block, `length` is its
+  // frames if known, only used to report the progress. Separators which can not
+  // stream take the whole mixture and separate it once it is finished
+  fn separate_stream<'a>(
+    &'a self,
+    length: Option<usize>,
+    progress: &'a dyn ProgressObserver,
+    cancel: &'a CancelToken,
+  ) -> Result<Box<dyn SeparatorStream + 'a>> {
+    let _ = length;
+
+    Ok(Box::new(WholeStream {
+      separator: self,
+      progress,
+      cancel,
+      mix: Array2::zeros((2, 0)),
+    }))
+  }
+}
+
+// The mixture fed to `Separator::separate_stream`. Both calls return the frames of
+// the stems finished so far in the order of `stem_names`, or none while there are
+// no finished frames
+pub trait SeparatorStream {
+  fn push(&mut self, block: ArrayView2<f64>) -> Result<Vec<Array2<f64>>>;
+
+  // Separates the rest, once the whole mixture is pushed
+  fn finish(self: Box<Self>) -> Result<Vec<Array2<f64>>>;
+}
+
+// Keeps the whole mixture, and separates it at once when it is finished
+struct WholeStream<'a, S: ?Sized> {
+  separator: &'a S,
+  progress: &'a dyn ProgressObserver,
+  cancel: &'a CancelToken,
+  mix: Array2<f64>,
+}
+
+impl<S: Separator + ?Sized> SeparatorStream for WholeStream<'_, S> {
+  fn push(&mut self, block: ArrayView2<f64>) -> Result<Vec<Array2<f64>>> {
+    self.cancel.check()?;
+    self
+      .mix
+      .append(Axis(1), block)
+      .map_err(|err| Error::Audio(err.into()))?;
+
+    Ok(Vec::new())
+  }
+
+  fn finish(self: Box<Self>) -> Result<Vec<Array2<f64>>> {
+    let mix = AudioBuffer::new(self.mix, self.separator.sample_rate())?;
+    let stems = self
+      .separator
+      .separate_with(&mix, self.progress, self.cancel)?;
+
+    Ok(
+      stems
+        .into_iter()
+        .map(|(_, wave)| wave.into_samples())
+        .collect(),
+    )
+  }
+}
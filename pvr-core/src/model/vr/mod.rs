@@ -8,6 +8,19 @@ use burn::{
   tensor::{activation::sigmoid, backend::Backend, Tensor},
 };
 
+#[cfg(feature = "burn-ndarray")]
+pub type NdArrayBackend = burn::backend::NdArray;
+#[cfg(feature = "burn-wgpu")]
+pub type WgpuBackend = burn::backend::Wgpu;
+
+// Backends of burn compiled into this build
+pub const BACKENDS: &[&str] = &[
+  #[cfg(feature = "burn-ndarray")]
+  "ndarray",
+  #[cfg(feature = "burn-wgpu")]
+  "wgpu",
+];
+
 #[derive(Debug, Module)]
 struct BaseASPPNet<B: Backend> {
   enc1: layers::Encoder<B>,
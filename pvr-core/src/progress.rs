@@ -0,0 +1,52 @@
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Stage {
+  Decode,
+  Resample,
+  // running the model on the chunks
+  Separate,
+  Encode,
+}
+
+impl fmt::Display for Stage {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Stage::Decode => write!(f, "Decoding"),
+      Stage::Resample => write!(f, "Resampling"),
+      Stage::Separate => write!(f, "Separating"),
+      Stage::Encode => write!(f, "Encoding"),
+    }
+  }
+}
+
+// `done` out of `total` units of the stage, which are chunks when separating
+// and frames otherwise. `total` is unknown for some streams when decoding
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Progress {
+  pub stage: Stage,
+  pub done: u64,
+  pub total: Option<u64>,
+}
+
+impl Progress {
+  pub fn new(stage: Stage, done: u64, total: Option<u64>) -> Self {
+    Self { stage, done, total }
+  }
+}
+
+// Receives the progress of every stage, called on the working thread
+pub trait ProgressObserver: Sync {
+  fn on_progress(&self, progress: Progress);
+}
+
+// Ignores the progress
+impl ProgressObserver for () {
+  fn on_progress(&self, _progress: Progress) {}
+}
+
+impl<F: Fn(Progress) + Sync> ProgressObserver for F {
+  fn on_progress(&self, progress: Progress) {
+    self(progress)
+  }
+}
@@ -0,0 +1,256 @@
+use std::{
+  collections::HashMap,
+  panic,
+  pin::Pin,
+  sync::{Arc, Mutex},
+  task::{Context, Poll},
+};
+
+use tokio::{
+  sync::{mpsc, OnceCell},
+  task::{self, JoinError, JoinHandle},
+};
+use tokio_stream::{wrappers::UnboundedReceiverStream, Stream};
+
+#[cfg(feature = "mdx")]
+use crate::mdx::MdxConfig;
+use crate::{
+  audio::AudioBuffer, cancel::CancelToken, progress::Progress, Error, Result, Separator, Stems,
+};
+
+// A separator shared by the tasks, the sessions of its model are loaded once
+pub type SharedSeparator = Arc<dyn Separator + Send + Sync>;
+
+// The separators loaded so far by their keys. A model asked for by many tasks
+// at once is only built once, while the loads of other keys go on meanwhile
+#[derive(Default)]
+pub struct Models {
+  // the lock is only held to find the cell of a key, never during a load
+  loaded: Mutex<HashMap<String, Arc<OnceCell<SharedSeparator>>>>,
+}
+
+impl Models {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  // Returns the separator loaded as `key`, or builds it on the blocking pool
+  pub async fn load<S, F>(&self, key: impl Into<String>, build: F) -> Result<SharedSeparator>
+  where
+    S: Separator + Send + Sync + 'static,
+    F: FnOnce() -> Result<S> + Send + 'static,
+  {
+    let cell = self
+      .loaded
+      .lock()
+      .expect("The models are poisoned")
+      .entry(key.into())
+      .or_default()
+      .clone();
+
+    // a failed load leaves the cell empty, the next one tries again
+    let separator = cell
+      .get_or_try_init(|| async move {
+        let separator: SharedSeparator = Arc::new(run_blocking(build).await?);
+        Ok(separator)
+      })
+      .await?;
+
+    Ok(separator.clone())
+  }
+
+  // Loaded as `MdxConfig::cache_key`, so the same file with other options
+  // is loaded again
+  #[cfg(feature = "mdx")]
+  pub async fn load_mdx(&self, config: MdxConfig) -> Result<SharedSeparator> {
+    self.load(config.cache_key(), move || config.build()).await
+  }
+
+  // The tasks running on the separator keep it until they finish
+  pub fn unload(&self, key: &str) -> Option<SharedSeparator> {
+    self
+      .loaded
+      .lock()
+      .expect("The models are poisoned")
+      .remove(key)
+      .and_then(|cell| cell.get().cloned())
+  }
+}
+
+// The progress of a task, which ends when the task finishes
+pub struct ProgressEvents(UnboundedReceiverStream<Progress>);
+
+impl Stream for ProgressEvents {
+  type Item = Progress;
+
+  fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Progress>> {
+    Pin::new(&mut self.0).poll_next(cx)
+  }
+}
+
+// A separation running on the blocking pool, so the executor is not blocked.
+// It is cancelled when dropped before it is joined
+pub struct SeparationTask {
+  handle: JoinHandle<Result<Stems>>,
+  progress: Option<ProgressEvents>,
+  cancel: CancelToken,
+  joined: bool,
+}
+
+impl SeparationTask {
+  pub fn spawn(separator: SharedSeparator, mix: AudioBuffer) -> Self {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let cancel = CancelToken::new();
+
+    let token = cancel.clone();
+    let handle = task::spawn_blocking(move || {
+      // nobody listening is not a reason to stop
+      let progress = move |progress: Progress| {
+        let _ = tx.send(progress);
+      };
+      separator.separate_with(&mix, &progress, &token)
+    });
+
+    Self {
+      handle,
+      progress: Some(ProgressEvents(UnboundedReceiverStream::new(rx))),
+      cancel,
+      joined: false,
+    }
+  }
+
+  // Only the first call gets the events
+  pub fn progress(&mut self) -> Option<ProgressEvents> {
+    self.progress.take()
+  }
+
+  // The task fails with `Error::Cancelled` at the next chunk once it is cancelled
+  pub fn cancel_handle(&self) -> CancelToken {
+    self.cancel.clone()
+  }
+
+  pub async fn join(mut self) -> Result<Stems> {
+    let res = (&mut self.handle).await;
+    self.joined = true;
+
+    res.unwrap_or_else(join_failed)
+  }
+}
+
+impl Drop for SeparationTask {
+  fn drop(&mut self) {
+    if !self.joined {
+      self.cancel.cancel();
+    }
+  }
+}
+
+// Separates on the blocking pool, the work stops if the future is dropped
+pub async fn separate(separator: SharedSeparator, mix: AudioBuffer) -> Result<Stems> {
+  SeparationTask::spawn(separator, mix).join().await
+}
+
+async fn run_blocking<T, F>(f: F) -> Result<T>
+where
+  T: Send + 'static,
+  F: FnOnce() -> Result<T> + Send + 'static,
+{
+  task::spawn_blocking(f).await.unwrap_or_else(join_failed)
+}
+
+// A panic of the work is passed on, otherwise the runtime is shutting down
+fn join_failed<T>(err: JoinError) -> Result<T> {
+  if err.is_panic() {
+    panic::resume_unwind(err.into_panic());
+  }
+
+  Err(Error::Cancelled)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    thread,
+    time::Duration,
+  };
+
+  use ndarray::Array2;
+
+  use super::*;
+  use crate::progress::ProgressObserver;
+
+  // Runs until it is cancelled
+  #[derive(Default)]
+  struct Waiting {
+    stopped: Arc<AtomicBool>,
+  }
+
+  impl Separator for Waiting {
+    fn sample_rate(&self) -> u32 {
+      44100
+    }
+
+    fn stem_names(&self) -> Vec<&str> {
+      Vec::new()
+    }
+
+    fn separate_with(
+      &self,
+      _mix: &AudioBuffer,
+      _progress: &dyn ProgressObserver,
+      cancel: &CancelToken,
+    ) -> Result<Stems> {
+      while !cancel.is_cancelled() {
+        thread::sleep(Duration::from_millis(1));
+      }
+      self.stopped.store(true, Ordering::Relaxed);
+
+      Err(Error::Cancelled)
+    }
+  }
+
+  #[tokio::test]
+  async fn loads_each_key_once() {
+    let models = Models::new();
+    let builds = Arc::new(AtomicUsize::new(0));
+    let load = |key: &'static str| {
+      let builds = builds.clone();
+      models.load(key, move || {
+        builds.fetch_add(1, Ordering::Relaxed);
+        Ok(Waiting::default())
+      })
+    };
+
+    let (first, second) = tokio::join!(load("a"), load("a"));
+    first.unwrap();
+    second.unwrap();
+    load("a").await.unwrap();
+    assert_eq!(builds.load(Ordering::Relaxed), 1);
+
+    load("b").await.unwrap();
+    assert_eq!(builds.load(Ordering::Relaxed), 2);
+
+    assert!(models.unload("a").is_some());
+    load("a").await.unwrap();
+    assert_eq!(builds.load(Ordering::Relaxed), 3);
+  }
+
+  #[tokio::test]
+  async fn dropping_the_task_cancels_it() {
+    let separator = Waiting::default();
+    let stopped = separator.stopped.clone();
+    let mix = AudioBuffer::new(Array2::zeros((2, 16)), 44100).unwrap();
+
+    let task = SeparationTask::spawn(Arc::new(separator), mix);
+    drop(task);
+
+    let stopped = task::spawn_blocking(move || {
+      (0..5000).any(|_| {
+        thread::sleep(Duration::from_millis(1));
+        stopped.load(Ordering::Relaxed)
+      })
+    });
+    assert!(stopped.await.unwrap());
+  }
+}
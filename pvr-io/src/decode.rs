@@ -0,0 +1,343 @@
+use std::{
+  fs::File,
+  io::{self, Read, Seek, SeekFrom},
+  path::Path,
+};
+
+use anyhow::{anyhow, bail, ensure, Result};
+use ndarray::{concatenate, Array2, Axis};
+use pvr_core::{
+  audio::{AudioBuffer, AudioMetadata},
+  progress::{Progress, ProgressObserver, Stage},
+};
+use symphonia::core::{
+  audio::{AudioBufferRef, Signal},
+  codecs::{Decoder, CODEC_TYPE_NULL},
+  conv::IntoSample,
+  errors::Error as SymphoniaError,
+  formats::FormatReader,
+  io::{MediaSource, MediaSourceStream},
+  probe::Hint,
+};
+
+/// Decodes the audio file at `path`, at its own sample rate and channel layout.
+/// The decoded frames are reported to `progress`, pass `&()` to ignore them.
+#[tracing::instrument(skip_all)]
+pub fn read_audio(path: impl AsRef<Path>, progress: &dyn ProgressObserver) -> Result<AudioBuffer> {
+  let path = path.as_ref();
+  let audio = AudioReader::open(path)?.read_to_end(progress)?;
+
+  tracing::info!(?path, "Audio read");
+
+  Ok(audio)
+}
+
+/// Decodes the audio from any seekable source, such as a `Cursor` over the
+/// bytes of a file. `extension` helps to guess the container format.
+#[tracing::instrument(skip_all)]
+pub fn read_audio_from<R>(
+  source: R,
+  extension: Option<&str>,
+  progress: &dyn ProgressObserver,
+) -> Result<AudioBuffer>
+where
+  R: Read + Seek + Send + Sync + 'static,
+{
+  AudioReader::from_source(source, extension)?.read_to_end(progress)
+}
+
+// `MediaSource` is only implemented for files and cursors by symphonia
+struct Source<R>(R);
+
+impl<R: Read> Read for Source<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    self.0.read(buf)
+  }
+}
+
+impl<R: Seek> Seek for Source<R> {
+  fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+    self.0.seek(pos)
+  }
+}
+
+impl<R: Read + Seek + Send + Sync> MediaSource for Source<R> {
+  fn is_seekable(&self) -> bool {
+    true
+  }
+
+  fn byte_len(&self) -> Option<u64> {
+    None
+  }
+}
+
+/// Decodes an audio file packet by packet, so the whole file never has to
+/// be in memory. The blocks are [channels, frames] at the rate of the file.
+pub struct AudioReader {
+  format: Box<dyn FormatReader>,
+  decoder: Box<dyn Decoder>,
+  track_id: u32,
+  sample_rate: u32,
+  channels: usize,
+  total_frames: Option<u64>,
+  metadata: AudioMetadata,
+  // decoded to find out the sample rate or the channels
+  pending: Option<Array2<f64>>,
+  decoded: u64,
+}
+
+impl AudioReader {
+  /// Opens the audio file at `path`.
+  pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+    let path = path.as_ref();
+    let src = File::open(path)?;
+
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+      hint.with_extension(extension);
+    }
+
+    let mut reader = Self::new(Box::new(src), hint)?;
+    reader.metadata.source = Some(path.to_path_buf());
+
+    Ok(reader)
+  }
+
+  /// Opens the audio from any seekable source, `extension` helps to guess
+  /// the container format.
+  pub fn from_source<R>(source: R, extension: Option<&str>) -> Result<Self>
+  where
+    R: Read + Seek + Send + Sync + 'static,
+  {
+    let mut hint = Hint::new();
+    if let Some(extension) = extension {
+      hint.with_extension(extension);
+    }
+
+    Self::new(Box::new(Source(source)), hint)
+  }
+
+  fn new(src: Box<dyn MediaSource>, hint: Hint) -> Result<Self> {
+    let mss = MediaSourceStream::new(src, Default::default());
+
+    let probed = symphonia::default::get_probe().format(
+      &hint,
+      mss,
+      &Default::default(),
+      &Default::default(),
+    )?;
+
+    let mut format = probed.format;
+    let track = format
+      .tracks()
+      .iter()
+      .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+      .ok_or_else(|| anyhow!("No supported audio tracks"))?;
+
+    let decoder =
+      symphonia::default::get_codecs().make(&track.codec_params, &Default::default())?;
+
+    let codec = symphonia::default::get_codecs()
+      .get_codec(track.codec_params.codec)
+      .map(|c| c.short_name.to_owned());
+
+    let track_id = track.id;
+    let total_frames = track.codec_params.n_frames;
+    let sample_rate = track.codec_params.sample_rate;
+    let channels = track.codec_params.channels.map(|c| c.count());
+
+    let mut tags = Vec::new();
+    if let Some(revision) = format.metadata().current() {
+      for tag in revision.tags() {
+        tags.push((tag.key.clone(), tag.value.to_string()));
+      }
+    }
+
+    let mut reader = Self {
+      format,
+      decoder,
+      track_id,
+      sample_rate: sample_rate.unwrap_or_default(),
+      channels: channels.unwrap_or_default(),
+      total_frames,
+      metadata: AudioMetadata {
+        source: None,
+        codec,
+        tags,
+      },
+      pending: None,
+      decoded: 0,
+    };
+
+    // the parameters are missing from some containers, take them from the first packet
+    if sample_rate.is_none() || channels.is_none() {
+      reader.pending = reader.decode_packet()?;
+      if reader.pending.is_none() {
+        bail!("Can not get sample rate");
+      }
+    }
+
+    Ok(reader)
+  }
+
+  pub fn sample_rate(&self) -> u32 {
+    self.sample_rate
+  }
+
+  pub fn channels(&self) -> usize {
+    self.channels
+  }
+
+  /// The length of the audio, if given by the container.
+  pub fn total_frames(&self) -> Option<u64> {
+    self.total_frames
+  }
+
+  /// Frames decoded so far.
+  pub fn decoded_frames(&self) -> u64 {
+    self.decoded
+  }
+
+  pub fn metadata(&self) -> &AudioMetadata {
+    &self.metadata
+  }
+
+  /// Decodes the next packet, `None` at the end of the stream.
+  pub fn next_block(&mut self) -> Result<Option<Array2<f64>>> {
+    match self.pending.take() {
+      Some(block) => Ok(Some(block)),
+      None => self.decode_packet(),
+    }
+  }
+
+  /// Decodes the rest of the audio into one buffer, reporting the decoded
+  /// frames to `progress`.
+  pub fn read_to_end(mut self, progress: &dyn ProgressObserver) -> Result<AudioBuffer> {
+    tracing::info!("Start decoding...");
+
+    let mut blocks = Vec::new();
+    while let Some(block) = self.next_block()? {
+      blocks.push(block);
+      progress.on_progress(Progress::new(
+        Stage::Decode,
+        self.decoded,
+        self.total_frames,
+      ));
+    }
+
+    let views: Vec<_> = blocks.iter().map(|b| b.view()).collect();
+    let res = if views.is_empty() {
+      Array2::zeros((self.channels, 0))
+    } else {
+      concatenate(Axis(1), &views)?
+    };
+
+    tracing::info!(samples = res.ncols(), "Audio decoded");
+
+    Ok(AudioBuffer::new(res, self.sample_rate)?.with_metadata(self.metadata))
+  }
+
+  fn decode_packet(&mut self) -> Result<Option<Array2<f64>>> {
+    loop {
+      let packet = match self.format.next_packet() {
+        Ok(packet) => packet,
+        // the tracks of a chained stream changed, the decoder would have to be
+        // rebuilt for the new ones, which may not match the audio decoded so far
+        Err(SymphoniaError::ResetRequired) => {
+          bail!("The tracks of the stream changed, which is not supported");
+        }
+        Err(SymphoniaError::IoError(err))
+          if err.kind() == std::io::ErrorKind::UnexpectedEof
+            && err.to_string() == "end of stream" =>
+        {
+          return Ok(None);
+        }
+        Err(err) => {
+          bail!(err);
+        }
+      };
+
+      if packet.track_id() != self.track_id {
+        tracing::warn!(
+          timestamp = packet.ts,
+          "The packet does not belong to the selected track, skip..."
+        );
+        continue;
+      }
+
+      match self.decoder.decode(&packet) {
+        Ok(decoded) => {
+          let spec = decoded.spec();
+          let channel_num = spec.channels.count();
+
+          if self.sample_rate == 0 {
+            self.sample_rate = spec.rate;
+          }
+          if self.channels == 0 {
+            self.channels = channel_num;
+          }
+          ensure!(
+            channel_num == self.channels,
+            "The number of channels changes from {} to {channel_num}",
+            self.channels
+          );
+
+          let mut block = Array2::zeros((channel_num, decoded.frames()));
+
+          macro_rules! copy_samples {
+            ($(($enum:ident, $type:ty)),*) => {
+              match &decoded {
+                $(
+                  AudioBufferRef::$enum(buf) => {
+                    let f = |&v| <$type as IntoSample<f64>>::into_sample(v);
+                    for (ch, mut row) in block.outer_iter_mut().enumerate() {
+                      row.iter_mut().zip(buf.chan(ch)).for_each(|(a, b)| *a = f(b));
+                    }
+                  }
+                )*
+                AudioBufferRef::F64(buf) => {
+                  for (ch, mut row) in block.outer_iter_mut().enumerate() {
+                    row.iter_mut().zip(buf.chan(ch)).for_each(|(a, &b)| *a = b);
+                  }
+                }
+              }
+            }
+          }
+
+          copy_samples!(
+            (U8, u8),
+            (U16, u16),
+            (U24, symphonia::core::sample::u24),
+            (U32, u32),
+            (S8, i8),
+            (S16, i16),
+            (S24, symphonia::core::sample::i24),
+            (S32, i32),
+            (F32, f32)
+          );
+
+          self.decoded += block.ncols() as u64;
+
+          return Ok(Some(block));
+        }
+        Err(SymphoniaError::IoError(_)) => {
+          tracing::error!(
+            timestamp = packet.ts,
+            "The packet failed to decode due to an IO error, skip..."
+          );
+          continue;
+        }
+        Err(SymphoniaError::DecodeError(_)) => {
+          tracing::warn!(
+            timestamp = packet.ts,
+            "The packet failed to decode due to invalid data, skip..."
+          );
+          continue;
+        }
+        Err(err) => {
+          bail!(err);
+        }
+      }
+    }
+  }
+}
@@ -0,0 +1,336 @@
+use std::{
+  fs::{self, File},
+  io::{BufWriter, Cursor, Seek, Write},
+  path::{Path, PathBuf},
+  str::FromStr,
+};
+
+use anyhow::{bail, ensure, Error, Result};
+use ndarray::{s, ArrayView2};
+use pvr_core::{
+  audio::AudioBuffer,
+  cancel::CancelToken,
+  progress::{Progress, ProgressObserver, Stage},
+};
+use symphonia::core::conv::IntoSample;
+
+/// The container of the encoded audio, both are 16-bit PCM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AudioFormat {
+  Wav,
+  Flac,
+}
+
+impl AudioFormat {
+  pub fn extension(&self) -> &'static str {
+    match self {
+      Self::Wav => "wav",
+      Self::Flac => "flac",
+    }
+  }
+}
+
+impl FromStr for AudioFormat {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "wav" => Ok(Self::Wav),
+      "flac" => Ok(Self::Flac),
+      _ => bail!("Unknown audio format `{s}`"),
+    }
+  }
+}
+
+// Frames encoded between two progress reports
+const BLOCK_FRAMES: usize = 1 << 16;
+
+/// Encodes the audio into a file at `path`, which is created or truncated.
+/// The encoded frames are reported to `progress`, pass `&()` to ignore them.
+/// `cancel` is checked between blocks, the partial file is removed on failure.
+#[tracing::instrument(skip_all)]
+pub fn write_audio(
+  path: impl AsRef<Path>,
+  audio: &AudioBuffer,
+  format: AudioFormat,
+  progress: &dyn ProgressObserver,
+  cancel: &CancelToken,
+) -> Result<()> {
+  let path = path.as_ref();
+  let mut writer = AudioWriter::create(path, format, audio.sample_rate(), audio.channels())?;
+
+  for_each_block(audio.samples(), progress, cancel, |block| {
+    writer.write(block)
+  })?;
+  writer.finish()?;
+
+  tracing::info!(?path, "Audio has been written");
+
+  Ok(())
+}
+
+enum FileEncoder {
+  Wav(hound::WavWriter<BufWriter<File>>),
+  Flac(libflac::InitializedEncoder<'static>),
+}
+
+/// Encodes the audio into a file block by block, so the length is not needed
+/// beforehand. The file is removed if dropped without `finish`.
+pub struct AudioWriter {
+  path: PathBuf,
+  channels: usize,
+  // taken while encoding, so the encoders consuming themselves can be used
+  encoder: Option<FileEncoder>,
+  finished: bool,
+}
+
+impl AudioWriter {
+  /// Creates or truncates the file at `path`.
+  pub fn create(
+    path: impl AsRef<Path>,
+    format: AudioFormat,
+    sample_rate: u32,
+    channels: usize,
+  ) -> Result<Self> {
+    let path = path.as_ref();
+
+    let encoder = match format {
+      AudioFormat::Wav => {
+        let spec = hound::WavSpec {
+          channels: channels.try_into()?,
+          sample_rate,
+          bits_per_sample: 16,
+          sample_format: hound::SampleFormat::Int,
+        };
+        FileEncoder::Wav(hound::WavWriter::create(path, spec)?)
+      }
+      // the STREAMINFO is rewritten when finishing, no estimate is needed
+      AudioFormat::Flac => FileEncoder::Flac(
+        libflac::Encoder::new()
+          .set_channels(channels as u32)
+          .set_bits_per_sample(16)
+          .set_sample_rate(sample_rate)
+          .set_compression_level(8)
+          .init_file(path)?,
+      ),
+    };
+
+    Ok(Self {
+      path: path.to_path_buf(),
+      channels,
+      encoder: Some(encoder),
+      finished: false,
+    })
+  }
+
+  /// Encodes the next frames, `block` is [channels, frames].
+  pub fn write(&mut self, block: ArrayView2<f64>) -> Result<()> {
+    ensure!(
+      block.nrows() == self.channels,
+      "Expected {} channels, got {}",
+      self.channels,
+      block.nrows()
+    );
+
+    let encoder = match self.encoder.take() {
+      Some(FileEncoder::Wav(mut writer)) => {
+        for &sample in block.t().iter() {
+          let sample: i16 = sample.into_sample();
+          writer.write_sample(sample)?;
+        }
+        FileEncoder::Wav(writer)
+      }
+      Some(FileEncoder::Flac(encoder)) => {
+        let data: Vec<i16> = block.t().iter().map(|&s| s.into_sample()).collect();
+        FileEncoder::Flac(encoder.process_interleaved(&data)?)
+      }
+      None => bail!("The encoder has already failed"),
+    };
+    self.encoder = Some(encoder);
+
+    Ok(())
+  }
+
+  /// Fixes the headers and closes the file.
+  pub fn finish(mut self) -> Result<()> {
+    match self.encoder.take() {
+      Some(FileEncoder::Wav(writer)) => writer.finalize()?,
+      Some(FileEncoder::Flac(encoder)) => {
+        encoder.finish()?;
+      }
+      None => bail!("The encoder has already failed"),
+    }
+    self.finished = true;
+
+    Ok(())
+  }
+}
+
+impl Drop for AudioWriter {
+  fn drop(&mut self) {
+    // not finished, or failed in the middle
+    if !self.finished {
+      // close the file first
+      drop(self.encoder.take());
+      if let Err(err) = fs::remove_file(&self.path) {
+        tracing::warn!(path = ?self.path, %err, "Failed to remove the partial file");
+      }
+    }
+  }
+}
+
+/// Encodes the audio into any writer, such as a `Vec<u8>`. WAV needs the
+/// length in its header, so it is encoded in memory first. The data already
+/// written stays in the sink when failed or cancelled.
+#[tracing::instrument(skip_all)]
+pub fn write_audio_to(
+  mut sink: impl Write,
+  audio: &AudioBuffer,
+  format: AudioFormat,
+  progress: &dyn ProgressObserver,
+  cancel: &CancelToken,
+) -> Result<()> {
+  match format {
+    AudioFormat::Wav => {
+      let mut buf = Cursor::new(Vec::new());
+      write_wav(&mut buf, audio, progress, cancel)?;
+      sink.write_all(buf.get_ref())?;
+    }
+    AudioFormat::Flac => write_flac(&mut sink, audio, progress, cancel)?,
+  }
+  sink.flush()?;
+
+  tracing::info!("Audio has been written");
+
+  Ok(())
+}
+
+// Calls `f` with every block of the audio, reporting the progress after each one
+fn for_each_block(
+  audio: ArrayView2<f64>,
+  progress: &dyn ProgressObserver,
+  cancel: &CancelToken,
+  mut f: impl FnMut(ArrayView2<f64>) -> Result<()>,
+) -> Result<()> {
+  let total = audio.ncols();
+  progress.on_progress(Progress::new(Stage::Encode, 0, Some(total as u64)));
+
+  for start in (0..total).step_by(BLOCK_FRAMES) {
+    cancel.check()?;
+
+    let end = (start + BLOCK_FRAMES).min(total);
+    f(audio.slice(s![.., start..end]))?;
+    progress.on_progress(Progress::new(Stage::Encode, end as u64, Some(total as u64)));
+  }
+
+  Ok(())
+}
+
+fn write_wav<W: Write + Seek>(
+  sink: W,
+  audio: &AudioBuffer,
+  progress: &dyn ProgressObserver,
+  cancel: &CancelToken,
+) -> Result<()> {
+  let spec = hound::WavSpec {
+    channels: audio.channels().try_into()?,
+    sample_rate: audio.sample_rate(),
+    bits_per_sample: 16,
+    sample_format: hound::SampleFormat::Int,
+  };
+
+  let mut writer = hound::WavWriter::new(sink, spec)?;
+
+  for_each_block(audio.samples(), progress, cancel, |block| {
+    for &sample in block.t().iter() {
+      let sample: i16 = sample.into_sample();
+      writer.write_sample(sample)?;
+    }
+    Ok(())
+  })?;
+
+  writer.finalize()?;
+
+  Ok(())
+}
+
+fn write_flac<W: Write>(
+  sink: &mut W,
+  audio: &AudioBuffer,
+  progress: &dyn ProgressObserver,
+  cancel: &CancelToken,
+) -> Result<()> {
+  use libflac::Encoder;
+
+  let mut encoder = Some(
+    Encoder::new()
+      .set_channels(audio.channels() as u32)
+      .set_bits_per_sample(16)
+      .set_sample_rate(audio.sample_rate())
+      .set_compression_level(8)
+      .set_total_samples_estimate(audio.frames() as u64)
+      .init_write(sink)?,
+  );
+
+  for_each_block(audio.samples(), progress, cancel, |block| {
+    let data: Vec<i16> = block.t().iter().map(|&s| s.into_sample()).collect();
+    let cur = encoder.take().expect("The encoder is always put back");
+    encoder = Some(cur.process_interleaved(&data)?);
+    Ok(())
+  })?;
+
+  encoder
+    .take()
+    .expect("The encoder is always put back")
+    .finish()?;
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use ndarray::Array2;
+
+  use super::*;
+  use crate::read_audio_from;
+
+  fn sine(frames: usize) -> AudioBuffer {
+    let samples = Array2::from_shape_fn((2, frames), |(row, i)| {
+      ((i + row * 13) as f64 * 0.05).sin() * 0.8
+    });
+    AudioBuffer::new(samples, 44100).unwrap()
+  }
+
+  #[test]
+  fn round_trip_in_memory() {
+    // longer than a block, so the encoders run more than once
+    let audio = sine(BLOCK_FRAMES + 1000);
+
+    for format in [AudioFormat::Wav, AudioFormat::Flac] {
+      let mut bytes = Vec::new();
+      write_audio_to(&mut bytes, &audio, format, &(), &CancelToken::new()).unwrap();
+      let res = read_audio_from(Cursor::new(bytes), Some(format.extension()), &()).unwrap();
+
+      assert_eq!(res.sample_rate(), audio.sample_rate(), "{format:?}");
+      assert_eq!(res.samples().dim(), audio.samples().dim(), "{format:?}");
+      // 16-bit PCM
+      let error = (&res.samples() - &audio.samples())
+        .iter()
+        .fold(0.0f64, |max, x| max.max(x.abs()));
+      assert!(error <= 1.0 / 32768.0, "{format:?} is off by {error}");
+    }
+  }
+
+  #[test]
+  fn unfinished_writer_removes_its_file() {
+    let audio = sine(1000);
+    let path = std::env::temp_dir().join(format!("pvr-io-unfinished-{}.wav", std::process::id()));
+
+    let mut writer = AudioWriter::create(&path, AudioFormat::Wav, 44100, 2).unwrap();
+    writer.write(audio.samples()).unwrap();
+    assert!(path.exists());
+
+    drop(writer);
+    assert!(!path.exists());
+  }
+}
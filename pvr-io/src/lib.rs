@@ -0,0 +1,17 @@
+//! Audio decoding and encoding of pvr.
+//!
+//! Decodes WAV, FLAC and MP3 into an [`AudioBuffer`] from a path or any
+//! `Read + Seek` source, and encodes WAV or 16-bit FLAC into a path or any
+//! `Write` sink, in-memory buffers included. [`AudioReader`] and
+//! [`AudioWriter`] work block by block for audio too long to keep in memory.
+
+mod decode;
+mod encode;
+
+pub use decode::{read_audio, read_audio_from, AudioReader};
+pub use encode::{write_audio, write_audio_to, AudioFormat, AudioWriter};
+pub use pvr_core::{
+  audio::{resample, AudioBuffer, AudioMetadata, ChannelLayout, StreamResampler},
+  cancel::{CancelToken, Cancelled},
+  progress::{Progress, ProgressObserver, Stage},
+};
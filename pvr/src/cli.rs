@@ -1,15 +1,20 @@
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
 
 #[cfg(target_os = "windows")]
 #[derive(Parser)]
+#[cfg_attr(not(feature = "mdx"), allow(dead_code))]
 #[command(version, author)]
 #[command(about = "A Rust port of UVR", long_about = None)]
+#[command(subcommand_negates_reqs = true)]
 pub struct Cli {
+  #[command(subcommand)]
+  pub command: Option<Command>,
+
   #[arg(short, long, help = "Input audio file path")]
-  #[arg(value_name = "INPUT")]
-  pub input_path: PathBuf,
+  #[arg(value_name = "INPUT", required = true)]
+  pub input_path: Option<PathBuf>,
 
   #[arg(short, long, help = "Directory to save output audio")]
   #[arg(value_name = "OUTPUT", default_value = ".")]
@@ -23,6 +28,10 @@ pub struct Cli {
   #[arg(value_name = "PRESET")]
   pub preset: Option<usize>,
 
+  #[arg(short, long, help = "Use an ONNX model file with embedded parameters")]
+  #[arg(value_name = "MODEL", conflicts_with = "preset")]
+  pub model: Option<PathBuf>,
+
   #[arg(short, long, help = "Use DirectML backend for inference")]
   pub directml_backend: bool,
 
@@ -32,19 +41,50 @@ pub struct Cli {
   #[arg(short, long, help = "Use TensorRT backend for inference")]
   pub tensorrt_backend: bool,
 
+  #[arg(long, help = "Use XNNPACK backend for inference")]
+  pub xnnpack_backend: bool,
+
+  #[arg(long, help = "Use oneDNN backend for inference")]
+  pub onednn_backend: bool,
+
+  #[cfg(feature = "tract")]
+  #[arg(
+    long,
+    help = "Use the built-in tract backend for inference, without ONNX Runtime"
+  )]
+  pub tract_backend: bool,
+
   #[arg(short, long, help = "File format used to save results (wav/flac)")]
   #[arg(value_name = "FORMAT", default_value = "flac")]
   pub format: String,
+
+  #[arg(
+    long,
+    help = "How to fill frequencies above the model cut-off (cut/mirror/mixture)"
+  )]
+  #[arg(value_name = "MODE", default_value = "cut")]
+  pub high_end: String,
+
+  #[command(flatten)]
+  pub session: SessionArgs,
+
+  #[command(flatten)]
+  pub parallel: ParallelArgs,
 }
 
 #[cfg(not(target_os = "windows"))]
 #[derive(Parser)]
+#[cfg_attr(not(feature = "mdx"), allow(dead_code))]
 #[command(version, author)]
 #[command(about = "A Rust port of UVR", long_about = None)]
+#[command(subcommand_negates_reqs = true)]
 pub struct Cli {
+  #[command(subcommand)]
+  pub command: Option<Command>,
+
   #[arg(short, long, help = "Input audio file path")]
-  #[arg(value_name = "INPUT")]
-  pub input_path: PathBuf,
+  #[arg(value_name = "INPUT", required = true)]
+  pub input_path: Option<PathBuf>,
 
   #[arg(short, long, help = "Directory to save output audio")]
   #[arg(value_name = "OUTPUT", default_value = ".")]
@@ -58,13 +98,167 @@ pub struct Cli {
   #[arg(value_name = "PRESET")]
   pub preset: Option<usize>,
 
+  #[arg(short, long, help = "Use an ONNX model file with embedded parameters")]
+  #[arg(value_name = "MODEL", conflicts_with = "preset")]
+  pub model: Option<PathBuf>,
+
   #[arg(short, long, help = "Use CUDA backend for inference")]
   pub cuda_backend: bool,
 
   #[arg(short, long, help = "Use TensorRT backend for inference")]
   pub tensorrt_backend: bool,
 
+  #[arg(long, help = "Use XNNPACK backend for inference")]
+  pub xnnpack_backend: bool,
+
+  #[arg(long, help = "Use oneDNN backend for inference")]
+  pub onednn_backend: bool,
+
+  #[cfg(feature = "tract")]
+  #[arg(
+    long,
+    help = "Use the built-in tract backend for inference, without ONNX Runtime"
+  )]
+  pub tract_backend: bool,
+
   #[arg(short, long, help = "File format used to save results (wav/flac)")]
   #[arg(value_name = "FORMAT", default_value = "flac")]
   pub format: String,
+
+  #[arg(
+    long,
+    help = "How to fill frequencies above the model cut-off (cut/mirror/mixture)"
+  )]
+  #[arg(value_name = "MODE", default_value = "cut")]
+  pub high_end: String,
+
+  #[command(flatten)]
+  pub session: SessionArgs,
+
+  #[command(flatten)]
+  pub parallel: ParallelArgs,
+}
+
+#[derive(Args)]
+#[cfg_attr(not(feature = "mdx"), allow(dead_code))]
+pub struct SessionArgs {
+  #[arg(long, help = "Threads used within an ONNX operator")]
+  #[arg(value_name = "N")]
+  pub intra_threads: Option<usize>,
+
+  #[arg(long, help = "Threads used to run ONNX operators in parallel")]
+  #[arg(value_name = "N")]
+  pub inter_threads: Option<usize>,
+
+  #[arg(long, help = "Disable the memory arena of the CPU backend")]
+  pub no_memory_arena: bool,
+
+  #[arg(long, help = "Disable the memory pattern optimization")]
+  pub no_memory_pattern: bool,
+
+  #[arg(long, help = "Graph optimization level of ONNX Runtime (0-3)")]
+  #[arg(value_name = "LEVEL", default_value = "3")]
+  #[arg(value_parser = clap::value_parser!(u8).range(0..=3))]
+  pub opt_level: u8,
+
+  #[arg(long, help = "Save the optimized ONNX model to this path")]
+  #[arg(value_name = "PATH")]
+  pub save_optimized_model: Option<PathBuf>,
+
+  #[arg(
+    long,
+    help = "Write the ONNX Runtime profiling result to a JSON file named after this prefix"
+  )]
+  #[arg(value_name = "PATH")]
+  pub profile: Option<PathBuf>,
+}
+
+#[derive(Args)]
+#[cfg_attr(not(feature = "mdx"), allow(dead_code))]
+pub struct ParallelArgs {
+  #[arg(long, help = "Chunks separated at the same time")]
+  #[arg(value_name = "N", default_value = "1")]
+  #[arg(value_parser = clap::value_parser!(u16).range(1..))]
+  pub workers: u16,
+
+  #[arg(
+    long,
+    help = "Sessions of the model shared by the workers, each one loads the model again"
+  )]
+  #[arg(value_name = "N", default_value = "1")]
+  #[arg(value_parser = clap::value_parser!(u16).range(1..))]
+  pub sessions: u16,
+
+  #[arg(
+    long,
+    help = "Run STFT, inference and iSTFT of consecutive chunks at the same time"
+  )]
+  pub pipeline: bool,
+
+  #[arg(long, help = "Threads used by STFT and iSTFT of each chunk")]
+  #[arg(value_name = "N", default_value = "1")]
+  #[arg(value_parser = clap::value_parser!(u16).range(1..))]
+  pub stft_threads: u16,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+  #[command(subcommand, about = "Manage models")]
+  Models(ModelsCommand),
+
+  #[command(about = "List the execution providers of the loaded ONNX Runtime")]
+  Backends,
+
+  #[command(about = "List the model families and backends compiled in")]
+  Features,
+}
+
+#[derive(Subcommand)]
+pub enum ModelsCommand {
+  #[command(about = "Write separation parameters into the metadata of an ONNX model")]
+  Annotate(AnnotateArgs),
+}
+
+#[derive(Args)]
+#[cfg_attr(not(feature = "mdx"), allow(dead_code))]
+pub struct AnnotateArgs {
+  #[arg(help = "ONNX model file to annotate")]
+  #[arg(value_name = "MODEL")]
+  pub model: PathBuf,
+
+  #[arg(
+    short,
+    long,
+    help = "Path to save the annotated model, overwrite the input if not given"
+  )]
+  #[arg(value_name = "OUTPUT")]
+  pub output_path: Option<PathBuf>,
+
+  #[arg(short, long, help = "Take the parameters from a preset")]
+  #[arg(value_name = "PRESET")]
+  pub preset: Option<usize>,
+
+  #[arg(long, help = "FFT size")]
+  pub n_fft: Option<usize>,
+
+  #[arg(long, help = "Hop length of STFT")]
+  pub hop_length: Option<usize>,
+
+  #[arg(long, help = "Number of frequency bins fed into the model")]
+  pub dim_f: Option<usize>,
+
+  #[arg(long, help = "Log2 of the number of frames fed into the model")]
+  pub dim_t: Option<u8>,
+
+  #[arg(long, help = "Volume compensation applied to the primary stem")]
+  pub compensate: Option<f64>,
+
+  #[arg(long, help = "Name of the primary stem")]
+  pub primary_stem: Option<String>,
+
+  #[arg(long, help = "Name of the secondary stem")]
+  pub secondary_stem: Option<String>,
+
+  #[arg(long, help = "Sample rate the model was trained at")]
+  pub sample_rate: Option<u32>,
 }
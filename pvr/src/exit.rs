@@ -0,0 +1,46 @@
+use std::process::ExitCode;
+
+use pvr_core::Error;
+
+// The exit status of each kind of failure, listed in the README.
+// The error is logged where it happens, only the kind is passed up
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(not(feature = "mdx"), allow(dead_code))]
+pub enum Failure {
+  Other = 1,
+  // invalid arguments, same as the status of clap
+  Usage = 2,
+  ModelNotFound = 3,
+  InvalidModel = 4,
+  BackendInit = 5,
+  Inference = 6,
+  Dsp = 7,
+  // the input audio can not be read
+  Input = 8,
+  // a stem can not be written
+  Output = 9,
+  // interrupted by Ctrl-C, same as the shells
+  Cancelled = 130,
+}
+
+impl From<Failure> for ExitCode {
+  fn from(failure: Failure) -> Self {
+    ExitCode::from(failure as u8)
+  }
+}
+
+impl From<&Error> for Failure {
+  fn from(err: &Error) -> Self {
+    match err {
+      Error::ModelNotFound(_) => Self::ModelNotFound,
+      Error::InvalidModel(_) => Self::InvalidModel,
+      Error::BackendInit(_) => Self::BackendInit,
+      Error::Inference(_) => Self::Inference,
+      Error::Dsp(_) => Self::Dsp,
+      Error::Config(_) => Self::Usage,
+      Error::Audio(_) => Self::Input,
+      Error::Io(_) => Self::Other,
+      Error::Cancelled => Self::Cancelled,
+    }
+  }
+}
@@ -1,105 +1,256 @@
 mod cli;
+mod exit;
+#[cfg(feature = "mdx")]
+mod pipeline;
+#[cfg(feature = "mdx")]
+mod progress;
 mod setup;
-mod util;
+
+use std::process::ExitCode;
 
 use clap::Parser;
-use pvr_core::mdx::MDX_PRESETS;
+#[cfg(feature = "mdx")]
+use pvr_core::{
+  config::{runtime_available, Backend},
+  mdx::{HighEnd, MdxConfig, MDX_PRESETS},
+  Separator,
+};
+
+use cli::{AnnotateArgs, Cli, Command, ModelsCommand};
+use exit::Failure;
+#[cfg(feature = "mdx")]
+use pipeline::separate_file;
+#[cfg(feature = "mdx")]
+use progress::ProgressReporter;
+#[cfg(feature = "mdx")]
+use pvr_io::{AudioFormat, AudioReader};
+use setup::setup_tracing;
+#[cfg(feature = "mdx")]
+use setup::{session_options, setup_cancel, setup_ort};
+
+// Without a model family, there is nothing to run
+#[cfg(not(feature = "mdx"))]
+fn no_family() -> Result<(), Failure> {
+  tracing::error!("pvr is built without MDX support, rebuild it with the `mdx` feature");
+  Err(Failure::Other)
+}
+
+#[cfg(not(feature = "mdx"))]
+fn annotate(_args: AnnotateArgs) -> Result<(), Failure> {
+  no_family()
+}
+
+#[cfg(feature = "mdx")]
+fn annotate(args: AnnotateArgs) -> Result<(), Failure> {
+  let mut meta = match args.preset {
+    Some(preset) => match MDX_PRESETS.get(preset) {
+      Some(preset) => preset.metadata(),
+      None => {
+        tracing::error!(preset, "Unknown preset");
+        return Err(Failure::Usage);
+      }
+    },
+    None => Default::default(),
+  };
+
+  macro_rules! override_meta {
+    ($($field:ident),*) => {
+      $(
+        if args.$field.is_some() {
+          meta.$field = args.$field;
+        }
+      )*
+    };
+  }
+
+  override_meta!(
+    n_fft,
+    hop_length,
+    dim_f,
+    dim_t,
+    compensate,
+    primary_stem,
+    secondary_stem,
+    sample_rate
+  );
+
+  let output_path = args.output_path.as_ref().unwrap_or(&args.model);
+
+  meta.annotate(&args.model, output_path).map_err(|err| {
+    tracing::error!(%err, "Failed to annotate the model");
+    Failure::from(&err)
+  })
+}
+
+#[cfg(not(feature = "mdx"))]
+fn list_backends() -> Result<(), Failure> {
+  no_family()
+}
+
+#[cfg(feature = "mdx")]
+fn list_backends() -> Result<(), Failure> {
+  if Backend::Tract.is_available() {
+    println!("Built-in backend: {}", Backend::Tract);
+  }
+
+  if !runtime_available() {
+    println!("ONNX Runtime can not be loaded");
+    return Ok(());
+  }
+
+  println!("Execution providers of the loaded ONNX Runtime:");
+  for backend in Backend::ALL {
+    if backend.is_onnxruntime() && backend.is_available() {
+      println!("- {} ({backend})", backend.provider_name());
+    }
+  }
+
+  Ok(())
+}
+
+fn list_features() -> Result<(), Failure> {
+  println!("Model families compiled in:");
+
+  #[cfg(feature = "mdx")]
+  println!("- mdx (engines: {})", pvr_core::mdx::ENGINES.join(", "));
+
+  #[cfg(feature = "vr")]
+  println!("- vr (backends: {})", pvr_core::vr::BACKENDS.join(", "));
 
-use cli::Cli;
-use setup::{setup_ort, setup_tracing};
-use util::{read_audio, write_audio, AudioFormat};
+  if pvr_core::MODEL_FAMILIES.is_empty() {
+    println!("- none");
+  }
+
+  Ok(())
+}
 
-fn main() {
+fn main() -> ExitCode {
   let args = Cli::parse();
 
-  let Some(preset) = args.preset else {
-    println!("Please specify the model you wish to use");
-    println!("All available models:");
-    for (id, p) in MDX_PRESETS.iter().enumerate() {
-      if p.exists() {
-        println!("{id}. {} ({})", p.name, p.model_type);
+  let res = match args.command {
+    Some(command) => {
+      setup_tracing();
+
+      match command {
+        Command::Models(ModelsCommand::Annotate(args)) => annotate(args),
+        Command::Backends => list_backends(),
+        Command::Features => list_features(),
+      }
+    }
+    None => separate(args),
+  };
+
+  match res {
+    Ok(()) => ExitCode::SUCCESS,
+    Err(failure) => failure.into(),
+  }
+}
+
+#[cfg(not(feature = "mdx"))]
+fn separate(_args: Cli) -> Result<(), Failure> {
+  setup_tracing();
+  no_family()
+}
+
+#[cfg(feature = "mdx")]
+fn separate(args: Cli) -> Result<(), Failure> {
+  let preset = match (&args.model, args.preset) {
+    (Some(model), _) => MdxConfig::from_file(model),
+    (None, Some(preset)) => match MDX_PRESETS.get(preset) {
+      Some(preset) => preset.clone(),
+      None => {
+        println!("Unknown preset {preset}, run without `--preset` to list them");
+        return Err(Failure::Usage);
+      }
+    },
+    (None, None) => {
+      println!("Please specify the model you wish to use");
+      println!("All available models:");
+      for (id, p) in MDX_PRESETS.iter().enumerate() {
+        if let (true, Some(model_type)) = (p.exists(), &p.model_type) {
+          println!("{id}. {} ({model_type})", p.name);
+        }
       }
+      // listing the models is what a run without a model asks for
+      return Ok(());
     }
-    return;
   };
 
+  let input_path = args.input_path.as_ref().expect("Input path is required");
+
   setup_tracing();
   setup_ort(&args);
 
-  let output_format = match args.format.as_str() {
-    "wav" => AudioFormat::Wav,
-    "flac" => AudioFormat::Flac,
-    _ => {
-      tracing::error!(format = args.format, "Unknown output audio format");
-      return;
+  let output_format: AudioFormat = match args.format.parse() {
+    Ok(format) => format,
+    Err(err) => {
+      tracing::error!(%err, "Unknown output audio format");
+      return Err(Failure::Usage);
+    }
+  };
+
+  let high_end: HighEnd = match args.high_end.parse() {
+    Ok(high_end) => high_end,
+    Err(err) => {
+      tracing::error!(%err, "Unknown high end mode");
+      return Err(Failure::Usage);
     }
   };
 
-  if !args.input_path.is_file() {
-    tracing::error!(input = ?args.input_path, "Input path is not regular file");
-    return;
+  if !input_path.is_file() {
+    tracing::error!(input = ?input_path, "Input path is not regular file");
+    return Err(Failure::Usage);
   }
 
   if !args.output_path.is_dir() {
     tracing::error!(output = ?args.output_path, "Output path is not directory");
-    return;
+    return Err(Failure::Usage);
   }
 
-  let preset = &MDX_PRESETS[preset];
-  let mdx = match preset.build() {
+  let mdx = match preset
+    .with_high_end(high_end)
+    .with_session_options(session_options(&args.session))
+    .with_workers(args.parallel.workers.into())
+    .with_sessions(args.parallel.sessions.into())
+    .with_pipeline(args.parallel.pipeline)
+    .with_stft_threads(args.parallel.stft_threads.into())
+    .build()
+  {
     Ok(mdx) => mdx,
     Err(err) => {
       tracing::error!(%err, "Failed to build the model");
-      return;
+      return Err(Failure::from(&err));
     }
   };
 
-  let mix = match read_audio(&args.input_path) {
-    Ok(mix) => mix,
-    Err(err) => {
-      tracing::error!(%err, "Failed to read audio");
-      return;
-    }
-  };
+  tracing::info!(backend = %mdx.backend(), "First registered backend");
 
-  let res = match mdx.demix(mix.view()) {
-    Ok(res) => res,
+  let reader = match AudioReader::open(input_path) {
+    Ok(reader) => reader,
     Err(err) => {
-      tracing::error!(%err, "Failed to inference");
-      return;
+      tracing::error!(%err, "Failed to read audio");
+      return Err(Failure::Input);
     }
   };
 
-  let origin_filename = args
-    .input_path
+  let origin_filename = input_path
     .file_stem()
     .expect("Failed to get input file stem")
     .to_string_lossy();
+  let outputs = mdx
+    .stem_names()
+    .into_iter()
+    .map(|stem| {
+      let filename = format!("{origin_filename}_{stem}.{}", output_format.extension());
+      args.output_path.join(filename)
+    })
+    .collect();
 
-  let primary_filename = format!(
-    "{origin_filename}_{}.{}",
-    preset.model_type.get_primary_stem(),
-    output_format.extension()
-  );
-  let secondary_filename = format!(
-    "{origin_filename}_{}.{}",
-    preset.model_type.get_secondary_stem(),
-    output_format.extension()
-  );
+  let progress = ProgressReporter::new();
+  let cancel = setup_cancel();
 
-  if let Err(err) = write_audio(
-    args.output_path.join(primary_filename),
-    res.view(),
-    &output_format,
-  ) {
-    tracing::error!(%err, "Failed to write the primary stem");
-  }
+  let res = separate_file(&mdx, reader, outputs, output_format, &progress, &cancel);
+  progress.finish();
 
-  if let Err(err) = write_audio(
-    args.output_path.join(secondary_filename),
-    (mix - res).view(),
-    &output_format,
-  ) {
-    tracing::error!(%err, "Failed to write the secondary stem");
-  }
+  res
 }
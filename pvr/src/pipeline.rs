@@ -0,0 +1,249 @@
+use std::{
+  path::PathBuf,
+  sync::mpsc::{self, Receiver, SyncSender},
+  thread,
+};
+
+use anyhow::ensure;
+use ndarray::Array2;
+use pvr_core::{Error, Separator};
+use pvr_io::{
+  AudioBuffer, AudioFormat, AudioReader, AudioWriter, CancelToken, Cancelled, ChannelLayout,
+  Progress, ProgressObserver, Stage, StreamResampler,
+};
+
+use crate::exit::Failure;
+
+// Blocks waiting between two stages, decoded packets are usually a few thousand frames
+const QUEUE_BLOCKS: usize = 64;
+
+// Decoding, separation and encoding run at the same time on their own threads,
+// connected by bounded queues, so only a few chunks of the song are in memory.
+// `None` ends a queue, a stage which finds its queue closed without it stops
+// quietly, as the stage which failed has already logged the error.
+// `outputs` are the paths of the stems, in the order of `stem_names`
+pub fn separate_file(
+  separator: &dyn Separator,
+  reader: AudioReader,
+  outputs: Vec<PathBuf>,
+  format: AudioFormat,
+  progress: &dyn ProgressObserver,
+  cancel: &CancelToken,
+) -> Result<(), Failure> {
+  let (sample_rate, target_rate) = (reader.sample_rate(), separator.sample_rate());
+  // only used to report the progress
+  let length = reader
+    .total_frames()
+    .map(|n| (n as u128 * target_rate as u128 / sample_rate as u128) as usize);
+
+  let (mix_tx, mix_rx) = mpsc::sync_channel(QUEUE_BLOCKS);
+  let (stem_tx, stem_rx) = mpsc::sync_channel(QUEUE_BLOCKS);
+
+  thread::scope(|s| {
+    let decoder = s.spawn(|| decode(reader, mix_tx, progress, cancel));
+    let encoder = s.spawn(|| {
+      encode(
+        stem_rx,
+        outputs,
+        format,
+        target_rate,
+        length,
+        progress,
+        cancel,
+      )
+    });
+
+    let separated = separate(
+      separator,
+      sample_rate,
+      length,
+      mix_rx,
+      stem_tx,
+      progress,
+      cancel,
+    );
+
+    // the failure closest to the input is the cause of the others
+    let decoded = decoder.join().expect("The decoder panicked");
+    let encoded = encoder.join().expect("The encoder panicked");
+    decoded.and(separated).and(encoded)
+  })
+}
+
+fn decode(
+  mut reader: AudioReader,
+  tx: SyncSender<Option<Array2<f64>>>,
+  progress: &dyn ProgressObserver,
+  cancel: &CancelToken,
+) -> Result<(), Failure> {
+  loop {
+    if cancel.is_cancelled() {
+      tracing::warn!("Decoding cancelled");
+      return Err(Failure::Cancelled);
+    }
+
+    let block = match reader.next_block() {
+      Ok(Some(block)) => block,
+      Ok(None) => break,
+      Err(err) => {
+        tracing::error!(%err, "Failed to read audio");
+        return Err(Failure::Input);
+      }
+    };
+
+    let stereo = AudioBuffer::new(block, reader.sample_rate())
+      .and_then(|block| block.to_layout(ChannelLayout::Stereo));
+    let stereo = match stereo {
+      Ok(stereo) => stereo,
+      Err(err) => {
+        tracing::error!(%err, "Failed to read audio");
+        return Err(Failure::Input);
+      }
+    };
+
+    progress.on_progress(Progress::new(
+      Stage::Decode,
+      reader.decoded_frames(),
+      reader.total_frames(),
+    ));
+
+    if tx.send(Some(stereo.into_samples())).is_err() {
+      return Ok(());
+    }
+  }
+
+  let _ = tx.send(None);
+
+  Ok(())
+}
+
+fn separate(
+  separator: &dyn Separator,
+  sample_rate: u32,
+  length: Option<usize>,
+  rx: Receiver<Option<Array2<f64>>>,
+  tx: SyncSender<Option<Vec<Array2<f64>>>>,
+  progress: &dyn ProgressObserver,
+  cancel: &CancelToken,
+) -> Result<(), Failure> {
+  match separate_blocks(separator, sample_rate, length, rx, tx, progress, cancel) {
+    Ok(()) => Ok(()),
+    Err(Error::Cancelled) => {
+      tracing::warn!("Separation cancelled");
+      Err(Failure::Cancelled)
+    }
+    Err(err) => {
+      tracing::error!(%err, "Failed to separate");
+      Err(Failure::from(&err))
+    }
+  }
+}
+
+fn separate_blocks(
+  separator: &dyn Separator,
+  sample_rate: u32,
+  length: Option<usize>,
+  rx: Receiver<Option<Array2<f64>>>,
+  tx: SyncSender<Option<Vec<Array2<f64>>>>,
+  progress: &dyn ProgressObserver,
+  cancel: &CancelToken,
+) -> pvr_core::Result<()> {
+  let mut resampler = StreamResampler::new(sample_rate, separator.sample_rate(), 2)?;
+  let mut stream = separator.separate_stream(length, progress, cancel)?;
+
+  loop {
+    let block = match rx.recv() {
+      Ok(Some(block)) => resampler.push(block.view())?,
+      Ok(None) => break,
+      Err(_) => return Ok(()),
+    };
+
+    let stems = stream.push(block.view())?;
+    if !stems.is_empty() && tx.send(Some(stems)).is_err() {
+      return Ok(());
+    }
+  }
+
+  let last = stream.push(resampler.finish()?.view())?;
+  let tail = stream.finish()?;
+
+  for stems in [last, tail] {
+    if !stems.is_empty() && tx.send(Some(stems)).is_err() {
+      return Ok(());
+    }
+  }
+  let _ = tx.send(None);
+
+  Ok(())
+}
+
+fn encode(
+  rx: Receiver<Option<Vec<Array2<f64>>>>,
+  outputs: Vec<PathBuf>,
+  format: AudioFormat,
+  sample_rate: u32,
+  length: Option<usize>,
+  progress: &dyn ProgressObserver,
+  cancel: &CancelToken,
+) -> Result<(), Failure> {
+  write_stems(rx, outputs, format, sample_rate, length, progress, cancel).map_err(|err| {
+    if err.is::<Cancelled>() {
+      tracing::warn!("Encoding cancelled");
+      return Failure::Cancelled;
+    }
+
+    tracing::error!(%err, "Failed to write the stems");
+    Failure::Output
+  })
+}
+
+fn write_stems(
+  rx: Receiver<Option<Vec<Array2<f64>>>>,
+  outputs: Vec<PathBuf>,
+  format: AudioFormat,
+  sample_rate: u32,
+  length: Option<usize>,
+  progress: &dyn ProgressObserver,
+  cancel: &CancelToken,
+) -> anyhow::Result<()> {
+  // created here, the encoders of libflac can not be sent to other threads
+  let mut writers = outputs
+    .iter()
+    .map(|path| AudioWriter::create(path, format, sample_rate, 2))
+    .collect::<Result<Vec<_>, _>>()?;
+  let mut written = 0;
+
+  loop {
+    let stems = match rx.recv() {
+      Ok(Some(stems)) => stems,
+      Ok(None) => break,
+      // the partial files are removed when the writers are dropped
+      Err(_) => return Ok(()),
+    };
+    cancel.check()?;
+
+    ensure!(
+      stems.len() == writers.len(),
+      "Got {} stems for {} files",
+      stems.len(),
+      writers.len()
+    );
+    for (writer, stem) in writers.iter_mut().zip(&stems) {
+      writer.write(stem.view())?;
+    }
+
+    written += stems[0].ncols() as u64;
+    progress.on_progress(Progress::new(
+      Stage::Encode,
+      written,
+      length.map(|n| n as u64),
+    ));
+  }
+
+  for (writer, path) in writers.into_iter().zip(&outputs) {
+    writer.finish()?;
+    tracing::info!(?path, "Audio has been written");
+  }
+
+  Ok(())
+}
@@ -0,0 +1,68 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use pvr_core::progress::{Progress, ProgressObserver, Stage};
+
+// Draws a bar for every stage, the stages of the pipeline run at the same time.
+// A stage starting over (e.g. encoding the next stem) gets a new bar
+#[derive(Default)]
+pub struct ProgressReporter {
+  bars: MultiProgress,
+  current: Mutex<HashMap<Stage, ProgressBar>>,
+}
+
+fn new_bar(stage: Stage, total: Option<u64>) -> ProgressBar {
+  let (bar, template) = match total {
+    Some(total) => (
+      ProgressBar::new(total),
+      "{msg:>10} [{wide_bar}] {percent:>3}% ({elapsed} / {eta})",
+    ),
+    None => (
+      ProgressBar::new_spinner(),
+      "{msg:>10} {spinner} {pos} ({elapsed})",
+    ),
+  };
+
+  bar.set_style(
+    ProgressStyle::with_template(template)
+      .expect("The template is valid")
+      .progress_chars("=> "),
+  );
+  bar.set_message(stage.to_string());
+  bar
+}
+
+impl ProgressReporter {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn finish(&self) {
+    for (_, bar) in self.current.lock().unwrap().drain() {
+      bar.finish();
+    }
+  }
+}
+
+impl ProgressObserver for ProgressReporter {
+  fn on_progress(&self, progress: Progress) {
+    let mut current = self.current.lock().unwrap();
+
+    let restart = match current.get(&progress.stage) {
+      Some(bar) => progress.done < bar.position(),
+      None => true,
+    };
+
+    if restart {
+      if let Some(bar) = current.remove(&progress.stage) {
+        bar.finish();
+      }
+      let bar = self.bars.add(new_bar(progress.stage, progress.total));
+      current.insert(progress.stage, bar);
+    }
+
+    if let Some(bar) = current.get(&progress.stage) {
+      bar.set_position(progress.done);
+    }
+  }
+}
@@ -1,10 +1,16 @@
-use pvr_core::config::Backend;
+#[cfg(feature = "mdx")]
+use pvr_core::{
+  cancel::CancelToken,
+  config::{Backend, OptimizationLevel, SessionOptions},
+};
+#[cfg(feature = "mdx")]
 use smallvec::SmallVec;
 
 use tracing::Level;
 use tracing_subscriber::FmtSubscriber;
 
-use crate::cli::Cli;
+#[cfg(feature = "mdx")]
+use crate::cli::{Cli, SessionArgs};
 
 pub fn setup_tracing() {
   let subscriber = FmtSubscriber::builder()
@@ -15,8 +21,9 @@ pub fn setup_tracing() {
   tracing::subscriber::set_global_default(subscriber).expect("Setting default subscriber failed");
 }
 
+#[cfg(feature = "mdx")]
 pub fn setup_ort(args: &Cli) {
-  let mut backends: SmallVec<[_; 3]> = SmallVec::new();
+  let mut backends: SmallVec<[_; 5]> = SmallVec::new();
 
   #[cfg(target_os = "windows")]
   if args.directml_backend {
@@ -31,6 +38,21 @@ pub fn setup_ort(args: &Cli) {
     backends.push(Backend::TensorRT);
   }
 
+  if args.xnnpack_backend {
+    backends.push(Backend::XNNPACK);
+  }
+
+  if args.onednn_backend {
+    backends.push(Backend::OneDNN);
+  }
+
+  // tract does not work with the execution providers of ONNX Runtime
+  #[cfg(feature = "tract")]
+  if args.tract_backend {
+    backends.clear();
+    backends.push(Backend::Tract);
+  }
+
   if backends.is_empty() {
     tracing::warn!("No backend is specified, use CPU for inference...");
     backends.push(Backend::CPU);
@@ -38,3 +60,57 @@ pub fn setup_ort(args: &Cli) {
 
   pvr_core::config::setup_backends(backends).expect("Init ort execution providers failed");
 }
+
+#[cfg(feature = "mdx")]
+pub fn session_options(args: &SessionArgs) -> SessionOptions {
+  let optimization_level = match args.opt_level {
+    0 => OptimizationLevel::Disable,
+    1 => OptimizationLevel::Level1,
+    2 => OptimizationLevel::Level2,
+    _ => OptimizationLevel::Level3,
+  };
+
+  let mut options = SessionOptions::new()
+    .with_memory_arena(!args.no_memory_arena)
+    .with_memory_pattern(!args.no_memory_pattern)
+    .with_optimization_level(optimization_level);
+
+  if let Some(threads) = args.intra_threads {
+    options = options.with_intra_threads(threads);
+  }
+
+  if let Some(threads) = args.inter_threads {
+    options = options.with_inter_threads(threads);
+  }
+
+  if let Some(path) = &args.save_optimized_model {
+    options = options.with_optimized_model_path(path);
+  }
+
+  if let Some(path) = &args.profile {
+    options = options.with_profiling(path);
+  }
+
+  options
+}
+
+// Ctrl-C cancels the job at the next chunk. The process is never exited from
+// the handler, so the partial outputs are still removed by their writers
+#[cfg(feature = "mdx")]
+pub fn setup_cancel() -> CancelToken {
+  let cancel = CancelToken::new();
+  let token = cancel.clone();
+
+  let res = ctrlc::set_handler(move || {
+    if !token.is_cancelled() {
+      tracing::warn!("Cancelling after the current chunk...");
+      token.cancel();
+    }
+  });
+
+  if let Err(err) = res {
+    tracing::warn!(%err, "Failed to set the Ctrl-C handler");
+  }
+
+  cancel
+}